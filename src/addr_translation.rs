@@ -1,16 +1,46 @@
 use core::ops::Deref;
 
 // Functions for accessing phys frames
-use x86_64::{PhysAddr, VirtAddr};
+use x86_64::{
+    PhysAddr, VirtAddr,
+    structures::paging::{PageSize as _, PhysFrame, Size4KiB},
+};
 
 use crate::*;
 
 pub trait TranslateToVirt {
-    fn to_virt(self, paging: &PagingConfig) -> VirtAddr;
+    /// Returns `None` if the memory is not in the direct map
+    fn to_virt(self, paging: &PagingConfig) -> Option<VirtAddr>;
+}
+
+/// Translates the start of a physical range that should be mapped in the direct map
+pub fn phys_range_to_virt(
+    start_addr: PhysAddr,
+    len: u64,
+    paging: &PagingConfig,
+) -> Option<VirtAddr> {
+    let offset = paging.direct_map()?;
+    if offset.is_mapped(start_addr, len) {
+        Some(VirtAddr::new(start_addr.as_u64() + offset.deref()))
+    } else {
+        None
+    }
 }
 
 impl TranslateToVirt for PhysAddr {
-    fn to_virt(self, paging: &PagingConfig) -> VirtAddr {
-        VirtAddr::new(self.as_u64() + paging.offset.deref())
+    fn to_virt(self, paging: &PagingConfig) -> Option<VirtAddr> {
+        phys_range_to_virt(self, 1, paging)
+    }
+}
+
+impl TranslateToVirt for PhysFrame {
+    fn to_virt(self, paging: &PagingConfig) -> Option<VirtAddr> {
+        phys_range_to_virt(self.start_address(), Size4KiB::SIZE, paging)
+    }
+}
+
+impl TranslateToVirt for Frame {
+    fn to_virt(self, paging: &PagingConfig) -> Option<VirtAddr> {
+        phys_range_to_virt(self.start_addr(), self.size().byte_len_u64(), paging)
     }
 }
@@ -0,0 +1,382 @@
+use core::ops::Range;
+
+use x86_64::{
+    PhysAddr,
+    structures::paging::{self, FrameAllocator, FrameDeallocator, PhysFrame},
+};
+
+use crate::*;
+
+/// Blocks of 4 KiB (order 0) up to 1 GiB (order 18)
+const ORDER_COUNT: usize = 19;
+
+/// Marks the end of a free list
+const NONE: u64 = u64::MAX;
+
+/// Stored at the start of every free block
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct FreeBlock {
+    next: u64,
+    prev: u64,
+}
+
+fn order_of(page_size: PageSize) -> usize {
+    match page_size {
+        PageSize::_4KiB => 0,
+        PageSize::_2MiB => 9,
+        PageSize::_1GiB => 18,
+    }
+}
+
+fn block_len(order: usize) -> u64 {
+    PageSize::_4KiB.byte_len_u64() << order
+}
+
+#[derive(Debug)]
+pub enum NewBuddyAllocatorError {
+    /// There are no RAM regions
+    NoMemory,
+    /// No RAM region is big enough to hold the allocator's bitmaps
+    NoSpaceForBitmaps,
+    /// The allocator's bitmaps or free blocks are not in the direct map
+    NotDirectlyMapped,
+}
+
+/// A buddy allocator which allocates and frees frames of any [`PageSize`], merging free blocks with their buddies.
+///
+/// Free blocks are kept in intrusive linked lists, which are stored in the free frames themselves and accessed through the direct map.
+/// Each order also has a bitmap of which blocks are free, so that buddies can be found without reading allocated memory.
+/// The bitmaps are stored in memory taken from the first RAM region that is big enough.
+#[derive(Debug)]
+pub struct BuddyAllocator {
+    config: PagingConfig,
+    /// 1 GiB aligned
+    base: PhysAddr,
+    /// The number of 4 KiB frames from `base` to the end of the last RAM region
+    frame_count: u64,
+    bitmaps: PhysAddr,
+    bitmap_range: Range<PhysAddr>,
+    free_lists: [u64; ORDER_COUNT],
+    free_bytes: u64,
+}
+
+impl BuddyAllocator {
+    /// Creates an allocator which has all [`PhysRegionKind::Ram`] regions as free memory.
+    ///
+    /// # Safety
+    /// The RAM regions must not be used by anything else, and must be in the direct map.
+    pub unsafe fn new(
+        config: PagingConfig,
+        regions: impl IntoIterator<Item = PhysRegion> + Clone,
+    ) -> Result<Self, NewBuddyAllocatorError> {
+        let ram_ranges = || {
+            regions
+                .clone()
+                .into_iter()
+                .filter(|region| region.kind == PhysRegionKind::Ram)
+                .map(|region| {
+                    region.range.start.align_up(PageSize::_4KiB.byte_len_u64())
+                        ..region.range.end.align_down(PageSize::_4KiB.byte_len_u64())
+                })
+                .filter(|range| range.start < range.end)
+        };
+        let start = ram_ranges()
+            .map(|range| range.start)
+            .min()
+            .ok_or(NewBuddyAllocatorError::NoMemory)?;
+        let end = ram_ranges().map(|range| range.end).max().unwrap();
+        let base = start.align_down(PageSize::_1GiB.byte_len_u64());
+        let frame_count = (end - base) / PageSize::_4KiB.byte_len_u64();
+        let bitmaps_len = (0..ORDER_COUNT)
+            .map(|order| bitmap_words(frame_count, order) * 8)
+            .sum::<u64>()
+            .next_multiple_of(PageSize::_4KiB.byte_len_u64());
+        let bitmaps = ram_ranges()
+            .find(|range| range.end - range.start >= bitmaps_len)
+            .ok_or(NewBuddyAllocatorError::NoSpaceForBitmaps)?
+            .start;
+        let mut allocator = Self {
+            config,
+            base,
+            frame_count,
+            bitmaps,
+            bitmap_range: bitmaps..bitmaps + bitmaps_len,
+            free_lists: [NONE; ORDER_COUNT],
+            free_bytes: 0,
+        };
+        let mut bitmap_slice = config
+            .phys_slice::<u64>(bitmaps, (bitmaps_len / 8) as usize)
+            .ok_or(NewBuddyAllocatorError::NotDirectlyMapped)?;
+        unsafe { bitmap_slice.as_slice_mut() }.fill(0);
+        for range in ram_ranges() {
+            for range in [
+                range.start..range.end.min(allocator.bitmap_range.start),
+                range.start.max(allocator.bitmap_range.end)..range.end,
+            ] {
+                if range.start < range.end
+                    && config.direct_map().is_none_or(|offset| {
+                        !offset.is_mapped(range.start, range.end - range.start)
+                    })
+                {
+                    return Err(NewBuddyAllocatorError::NotDirectlyMapped);
+                }
+                unsafe { allocator.free_range(range) };
+            }
+        }
+        Ok(allocator)
+    }
+
+    /// The number of bytes that are free
+    pub fn free_bytes(&self) -> u64 {
+        self.free_bytes
+    }
+
+    /// The memory used for the allocator's bitmaps, which is never allocated
+    pub fn bitmap_range(&self) -> Range<PhysAddr> {
+        self.bitmap_range.clone()
+    }
+
+    pub fn allocate(&mut self, page_size: PageSize) -> Option<Frame> {
+        let order = order_of(page_size);
+        let mut found_order = (order..ORDER_COUNT).find(|order| self.free_lists[*order] != NONE)?;
+        let addr = PhysAddr::new(self.free_lists[found_order]);
+        self.remove_free(addr, found_order);
+        // Give back the upper halves until the block is the right size
+        while found_order > order {
+            found_order -= 1;
+            self.push_free(addr + block_len(found_order), found_order);
+        }
+        self.free_bytes -= block_len(order);
+        Some(Frame::new(addr, page_size).unwrap())
+    }
+
+    /// Allocates a specific frame, if all of it is free
+    pub fn allocate_at(&mut self, frame: Frame) -> Option<Frame> {
+        self.allocate_block_at(frame.start_addr(), order_of(frame.size()))
+            .then_some(frame)
+    }
+
+    /// Allocates `len` bytes (rounded up to 4 KiB) of contiguous physical memory, starting at a multiple of `align` (a power of two) and ending at or below `max_phys_addr`.
+    /// Free it with [`BuddyAllocator::deallocate_contiguous`].
+    pub fn allocate_contiguous(
+        &mut self,
+        len: u64,
+        align: u64,
+        max_phys_addr: PhysAddr,
+    ) -> Option<PhysAddr> {
+        let len = len.max(1).next_multiple_of(PageSize::_4KiB.byte_len_u64());
+        let order = (0..ORDER_COUNT)
+            .find(|order| block_len(*order) >= len && block_len(*order) >= align)?;
+        // The lowest part of a free block is the part most likely to be below the limit
+        let start = (order..ORDER_COUNT).find_map(|block_order| {
+            let mut addr = self.free_lists[block_order];
+            while addr != NONE {
+                if addr + block_len(order) <= max_phys_addr.as_u64() {
+                    return Some(PhysAddr::new(addr));
+                }
+                addr = unsafe { self.block(addr).read() }.next;
+            }
+            None
+        })?;
+        self.allocate_block_at(start, order);
+        // Give back what is not needed at the end of the block
+        unsafe { self.free_range(start + len..start + block_len(order)) };
+        Some(start)
+    }
+
+    /// # Safety
+    /// The memory must have been allocated with [`BuddyAllocator::allocate_contiguous`] with the same `len`, and must not be used anymore
+    pub unsafe fn deallocate_contiguous(&mut self, start: PhysAddr, len: u64) {
+        let len = len.max(1).next_multiple_of(PageSize::_4KiB.byte_len_u64());
+        unsafe { self.free_range(start..start + len) };
+    }
+
+    /// Allocates the block of the given order at `addr`, if all of it is free
+    fn allocate_block_at(&mut self, addr: PhysAddr, order: usize) -> bool {
+        let Some((mut start, mut block_order)) = self
+            .free_block_containing(addr)
+            .filter(|(_, block_order)| *block_order >= order)
+        else {
+            return false;
+        };
+        self.remove_free(start, block_order);
+        // Give back the halves that do not contain the block
+        while block_order > order {
+            block_order -= 1;
+            let half = block_len(block_order);
+            if addr >= start + half {
+                self.push_free(start, block_order);
+                start += half;
+            } else {
+                self.push_free(start + half, block_order);
+            }
+        }
+        self.free_bytes -= block_len(order);
+        true
+    }
+
+    /// Returns `true` if the 4 KiB frame containing `addr` is free
+    pub fn is_free(&self, addr: PhysAddr) -> bool {
+        self.free_block_containing(addr).is_some()
+    }
+
+    /// # Safety
+    /// The frame must have been allocated from this allocator and must not be used anymore
+    pub unsafe fn deallocate(&mut self, frame: Frame) {
+        unsafe { self.free_block(frame.start_addr(), order_of(frame.size())) };
+    }
+
+    /// Frees a 4 KiB aligned range, using the largest blocks that fit
+    unsafe fn free_range(&mut self, range: Range<PhysAddr>) {
+        let mut addr = range.start;
+        while addr < range.end {
+            let order = (0..ORDER_COUNT)
+                .rev()
+                .find(|order| {
+                    addr.is_aligned(block_len(*order)) && range.end - addr >= block_len(*order)
+                })
+                .unwrap();
+            unsafe { self.free_block(addr, order) };
+            addr += block_len(order);
+        }
+    }
+
+    /// Frees a block, merging it with its buddy as long as the buddy is free
+    unsafe fn free_block(&mut self, mut addr: PhysAddr, mut order: usize) {
+        self.free_bytes += block_len(order);
+        while order < ORDER_COUNT - 1 {
+            let buddy = PhysAddr::new(addr.as_u64() ^ block_len(order));
+            if !self.is_block_free(buddy, order) {
+                break;
+            }
+            self.remove_free(buddy, order);
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        self.push_free(addr, order);
+    }
+
+    /// Returns the free block that contains `addr`, and its order.
+    /// Free blocks never overlap, so there is at most one.
+    fn free_block_containing(&self, addr: PhysAddr) -> Option<(PhysAddr, usize)> {
+        (0..ORDER_COUNT)
+            .map(|order| (addr.align_down(block_len(order)), order))
+            .find(|(start, order)| self.is_block_free(*start, *order))
+    }
+
+    fn bit_index(&self, addr: PhysAddr, order: usize) -> Option<(u64, u64)> {
+        if addr < self.base {
+            return None;
+        }
+        let block_index = ((addr - self.base) / PageSize::_4KiB.byte_len_u64()) >> order;
+        if block_index >= self.frame_count.div_ceil(1 << order) {
+            return None;
+        }
+        let words_before = (0..order)
+            .map(|order| bitmap_words(self.frame_count, order))
+            .sum::<u64>();
+        Some((words_before + block_index / 64, block_index % 64))
+    }
+
+    fn bitmap_word(&self, word_index: u64) -> PhysPtr<u64> {
+        self.config
+            .phys_ptr(self.bitmaps + word_index * 8)
+            .expect("the bitmaps were checked to be in the direct map")
+    }
+
+    fn is_block_free(&self, addr: PhysAddr, order: usize) -> bool {
+        self.bit_index(addr, order)
+            .is_some_and(|(word, bit)| unsafe { self.bitmap_word(word).read() } & (1 << bit) != 0)
+    }
+
+    fn set_free(&mut self, addr: PhysAddr, order: usize, is_free: bool) {
+        let (word, bit) = self.bit_index(addr, order).unwrap();
+        let ptr = self.bitmap_word(word);
+        let value = unsafe { ptr.read() };
+        unsafe {
+            ptr.write(if is_free {
+                value | (1 << bit)
+            } else {
+                value & !(1 << bit)
+            })
+        };
+    }
+
+    fn block(&self, addr: u64) -> PhysPtr<FreeBlock> {
+        self.config
+            .phys_ptr(PhysAddr::new(addr))
+            .expect("free blocks were checked to be in the direct map")
+    }
+
+    fn push_free(&mut self, addr: PhysAddr, order: usize) {
+        let head = self.free_lists[order];
+        unsafe {
+            self.block(addr.as_u64()).write(FreeBlock {
+                next: head,
+                prev: NONE,
+            })
+        };
+        if head != NONE {
+            let head_ptr = self.block(head);
+            unsafe {
+                head_ptr.write(FreeBlock {
+                    prev: addr.as_u64(),
+                    ..head_ptr.read()
+                })
+            };
+        }
+        self.free_lists[order] = addr.as_u64();
+        self.set_free(addr, order, true);
+    }
+
+    fn remove_free(&mut self, addr: PhysAddr, order: usize) {
+        let block = unsafe { self.block(addr.as_u64()).read() };
+        if block.prev == NONE {
+            self.free_lists[order] = block.next;
+        } else {
+            let prev_ptr = self.block(block.prev);
+            unsafe {
+                prev_ptr.write(FreeBlock {
+                    next: block.next,
+                    ..prev_ptr.read()
+                })
+            };
+        }
+        if block.next != NONE {
+            let next_ptr = self.block(block.next);
+            unsafe {
+                next_ptr.write(FreeBlock {
+                    prev: block.prev,
+                    ..next_ptr.read()
+                })
+            };
+        }
+        self.set_free(addr, order, false);
+    }
+}
+
+fn bitmap_words(frame_count: u64, order: usize) -> u64 {
+    frame_count.div_ceil(1 << order).div_ceil(64)
+}
+
+fn page_size_of<S: paging::PageSize>() -> PageSize {
+    match S::SIZE {
+        0x1000 => PageSize::_4KiB,
+        0x20_0000 => PageSize::_2MiB,
+        _ => PageSize::_1GiB,
+    }
+}
+
+unsafe impl<S: paging::PageSize> FrameAllocator<S> for BuddyAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
+        let frame = self.allocate(page_size_of::<S>())?;
+        Some(PhysFrame::from_start_address(frame.start_addr()).unwrap())
+    }
+}
+
+impl<S: paging::PageSize> FrameDeallocator<S> for BuddyAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        unsafe { self.deallocate(Frame::new(frame.start_address(), page_size_of::<S>()).unwrap()) };
+    }
+}
@@ -0,0 +1,39 @@
+// Functions for writing back and invalidating CPU caches
+use core::arch::asm;
+
+use raw_cpuid::CpuId;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::*;
+
+/// Writes back and invalidates every cache line of the physical range, accessing it through the direct map.
+/// If `clflush` is not supported, the range is too big for flushing line by line to make sense, or the range is not accessible, `wbinvd` is used instead.
+pub fn flush_phys_range(start_addr: PhysAddr, len: u64, config: &PagingConfig) {
+    let line_size = CpuId::new()
+        .get_feature_info()
+        .filter(|info| info.has_clflush())
+        .map(|info| u64::from(info.cflush_cache_line_size()) * 8)
+        .filter(|line_size| *line_size != 0);
+    match (line_size, phys_range_to_virt(start_addr, len, config)) {
+        (Some(line_size), Some(virt_addr)) if len < PageSize::_1GiB.byte_len_u64() => {
+            flush_virt_range(virt_addr, len, line_size);
+        }
+        _ => write_back_and_invalidate_all(),
+    }
+}
+
+fn flush_virt_range(start_addr: VirtAddr, len: u64, line_size: u64) {
+    let start = start_addr.align_down(line_size).as_u64();
+    let end = start_addr.as_u64() + len;
+    // `clflush` is only ordered with respect to other writes by `mfence`
+    unsafe { asm!("mfence", options(nostack, preserves_flags)) };
+    for line in (start..end).step_by(line_size as usize) {
+        unsafe { asm!("clflush [{}]", in(reg) line, options(nostack, preserves_flags)) };
+    }
+    unsafe { asm!("mfence", options(nostack, preserves_flags)) };
+}
+
+/// Writes back and invalidates all caches of this CPU
+pub fn write_back_and_invalidate_all() {
+    unsafe { asm!("wbinvd", options(nostack, preserves_flags)) };
+}
@@ -0,0 +1,119 @@
+use x86_64::PhysAddr;
+
+use crate::*;
+
+const FRAMES_PER_BLOCK: usize = 512;
+
+#[derive(Debug)]
+pub enum CompactError {
+    /// The block is not a 2 MiB frame
+    NotHugeFrame,
+    /// A frame in the block is pinned
+    Pinned(PhysAddr),
+    /// A frame in the block is used as a page table
+    PageTable(PhysAddr),
+    /// A frame in the block is part of a 2 MiB or 1 GiB frame that is mapped with a huge page
+    HugeMapping(PhysAddr),
+    /// A frame in the block is used, but not only through mappings that can be moved, or it is not in the [`FrameDatabase`]
+    NotMovable(PhysAddr),
+    /// There are not enough free frames outside of the block to move the used frames to
+    OutOfMemory,
+    /// Migrating a frame failed. Frames that were already migrated stay migrated, and the block is not allocated.
+    Migrate(MigrateFrameError),
+}
+
+impl PagingConfig {
+    /// Frees up a 2 MiB block of physical memory, by migrating the used 4 KiB frames in it to other frames from `frame_allocator`, and allocates the block.
+    ///
+    /// A used frame can be moved if its reference count in the attached [`FrameDatabase`] is 0 and it is mapped at least once, so that it is only used through its mappings.
+    /// The block is skipped if any used frame cannot be moved, such as pinned frames, page tables, and frames mapped by huge pages. Nothing is changed in that case.
+    /// The frames are moved with [`PagingConfig::migrate_frame`], which calls `flush_other_cpus`, so the frames given back to `frame_allocator` are not in the TLBs of other CPUs anymore.
+    /// Panics if no [`FrameDatabase`] or [`ReverseMap`] is attached.
+    ///
+    /// # Safety
+    /// Same as [`PagingConfig::migrate_frame`], for every used frame in the block.
+    /// Frames that are used but not tracked by `frame_allocator`, the database, or the reverse map must not be in the block.
+    pub unsafe fn compact_2mib(
+        &self,
+        block: Frame,
+        frame_allocator: &mut BuddyAllocator,
+        flush_other_cpus: &mut impl FnMut(&TlbShootdownBatch),
+    ) -> Result<Frame, CompactError> {
+        if block.size() != PageSize::_2MiB {
+            return Err(CompactError::NotHugeFrame);
+        }
+        let frame_database = self
+            .frame_database
+            .expect("compact_2mib needs a FrameDatabase attached to the PagingConfig");
+        let reverse_map = self
+            .reverse_map
+            .expect("compact_2mib needs a ReverseMap attached to the PagingConfig");
+        let frame = |i: usize| {
+            Frame::new(
+                block.start_addr() + (i * PageSize::_4KiB.byte_len()) as u64,
+                PageSize::_4KiB,
+            )
+            .unwrap()
+        };
+
+        let mut used_count = 0;
+        for i in 0..FRAMES_PER_BLOCK {
+            let addr = frame(i).start_addr();
+            if frame_allocator.is_free(addr) {
+                continue;
+            }
+            let meta = frame_database
+                .get(addr)
+                .ok_or(CompactError::NotMovable(addr))?;
+            if meta.flags().contains(FrameFlags::PINNED) {
+                return Err(CompactError::Pinned(addr));
+            }
+            if meta.flags().contains(FrameFlags::PAGE_TABLE) {
+                return Err(CompactError::PageTable(addr));
+            }
+            if meta.ref_count() != 0 || meta.map_count() == 0 {
+                return Err(CompactError::NotMovable(addr));
+            }
+            // Huge pages are only counted in the metadata of their first 4 KiB frame, and are not found as mappings of that 4 KiB frame
+            let mut mapping_count = 0;
+            reverse_map.for_each_mapping(frame(i), &mut |_| mapping_count += 1);
+            if meta.map_count() != mapping_count {
+                return Err(CompactError::HugeMapping(addr));
+            }
+            used_count += 1;
+        }
+
+        // Take the free frames in the block first, so that used frames are not migrated into the block
+        let mut claimed = [false; FRAMES_PER_BLOCK];
+        for (i, claimed) in claimed.iter_mut().enumerate() {
+            *claimed = frame_allocator.allocate_at(frame(i)).is_some();
+        }
+        let give_back = |frame_allocator: &mut BuddyAllocator, claimed: &[bool]| {
+            for (i, _) in claimed.iter().enumerate().filter(|(_, claimed)| **claimed) {
+                unsafe { frame_allocator.deallocate(frame(i)) };
+            }
+        };
+        // Free memory can always be split into 4 KiB frames, so the allocations below cannot fail
+        if frame_allocator.free_bytes() < used_count * PageSize::_4KiB.byte_len_u64() {
+            give_back(frame_allocator, &claimed);
+            return Err(CompactError::OutOfMemory);
+        }
+
+        for i in 0..FRAMES_PER_BLOCK {
+            if claimed[i] {
+                continue;
+            }
+            let new = frame_allocator.allocate(PageSize::_4KiB).unwrap();
+            match unsafe { self.migrate_frame(frame(i), new, flush_other_cpus) } {
+                Ok(()) => claimed[i] = true,
+                Err(e) => {
+                    unsafe { frame_allocator.deallocate(new) };
+                    // `migrate_frame` already flushed other CPUs for the frames that were migrated, so they can be reused
+                    give_back(frame_allocator, &claimed);
+                    return Err(CompactError::Migrate(e));
+                }
+            }
+        }
+        Ok(block)
+    }
+}
@@ -0,0 +1,362 @@
+// A minimal ELF64 parser, which only reads what is needed for mapping segments
+use core::ops::Range;
+
+use x86_64::{
+    VirtAddr,
+    registers::model_specific::PatMemoryType,
+    structures::paging::{FrameAllocator, FrameDeallocator, Size4KiB},
+};
+
+use crate::*;
+
+const MAX_SEGMENTS: usize = 16;
+
+pub(crate) const PT_LOAD: u32 = 1;
+const PT_INTERP: u32 = 3;
+pub(crate) const PT_PHDR: u32 = 6;
+const PT_GNU_RELRO: u32 = 0x6474_e552;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const EM_X86_64: u16 = 62;
+/// The size of an ELF64 program header
+const PROGRAM_HEADER_SIZE: u64 = 56;
+
+#[derive(Debug)]
+pub enum MapElfError {
+    /// The bytes do not start with the ELF magic number
+    NotElf,
+    /// The ELF is not a 64-bit little-endian x86_64 ELF
+    Unsupported,
+    /// A header or segment is outside of the ELF bytes, or a segment's size is invalid
+    Malformed,
+    /// A segment is both writable and executable
+    WriteAndExecute,
+    /// Two segments are in the same page
+    OverlappingSegments,
+    TooManySegments,
+    FrameAllocationFailed,
+    /// A frame from the frame allocator is not in the direct map
+    NotDirectlyMapped,
+    Map(MapPageError),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MappedSegment {
+    /// The start of the first page of the segment
+    pub start: VirtAddr,
+    /// The end of the last page of the segment
+    pub end: VirtAddr,
+    pub flags: ConfigurableFlags,
+}
+
+#[derive(Debug, Clone)]
+pub struct MappedElf {
+    /// The entry point, with the load bias added
+    pub entry_point: VirtAddr,
+    segments: [Option<MappedSegment>; MAX_SEGMENTS],
+    relro: Option<Range<VirtAddr>>,
+}
+
+impl MappedElf {
+    pub fn segments(&self) -> impl Iterator<Item = &MappedSegment> {
+        self.segments.iter().flatten()
+    }
+
+    /// The pages that should be made read-only after relocations are applied, from the `PT_GNU_RELRO` header
+    pub fn relro(&self) -> Option<Range<VirtAddr>> {
+        self.relro.clone()
+    }
+}
+
+pub(crate) struct ProgramHeader {
+    pub p_type: u32,
+    pub p_flags: u32,
+    pub p_offset: u64,
+    pub p_vaddr: u64,
+    pub p_filesz: u64,
+    pub p_memsz: u64,
+}
+
+fn read<const N: usize>(bytes: &[u8], offset: u64) -> Result<[u8; N], MapElfError> {
+    let start = usize::try_from(offset).map_err(|_| MapElfError::Malformed)?;
+    bytes
+        .get(start..start.checked_add(N).ok_or(MapElfError::Malformed)?)
+        .map(|bytes| bytes.try_into().unwrap())
+        .ok_or(MapElfError::Malformed)
+}
+
+pub(crate) fn read_u16(bytes: &[u8], offset: u64) -> Result<u16, MapElfError> {
+    read(bytes, offset).map(u16::from_le_bytes)
+}
+
+fn read_u32(bytes: &[u8], offset: u64) -> Result<u32, MapElfError> {
+    read(bytes, offset).map(u32::from_le_bytes)
+}
+
+pub(crate) fn read_u64(bytes: &[u8], offset: u64) -> Result<u64, MapElfError> {
+    read(bytes, offset).map(u64::from_le_bytes)
+}
+
+pub(crate) fn program_headers(
+    elf_bytes: &[u8],
+) -> Result<impl Iterator<Item = Result<ProgramHeader, MapElfError>>, MapElfError> {
+    if read::<4>(elf_bytes, 0)? != *b"\x7fELF" {
+        return Err(MapElfError::NotElf);
+    }
+    // EI_CLASS must be ELFCLASS64 and EI_DATA must be ELFDATA2LSB
+    if read::<2>(elf_bytes, 4)? != [2, 1] || read_u16(elf_bytes, 0x12)? != EM_X86_64 {
+        return Err(MapElfError::Unsupported);
+    }
+    let ph_offset = read_u64(elf_bytes, 0x20)?;
+    let ph_entry_size = u64::from(read_u16(elf_bytes, 0x36)?);
+    let ph_count = u64::from(read_u16(elf_bytes, 0x38)?);
+    // Smaller entries would make the fields overlap the next header
+    if ph_count > 0 && ph_entry_size < PROGRAM_HEADER_SIZE {
+        return Err(MapElfError::Malformed);
+    }
+    Ok((0..ph_count).map(move |i| {
+        let offset = i
+            .checked_mul(ph_entry_size)
+            .and_then(|offset| offset.checked_add(ph_offset))
+            .ok_or(MapElfError::Malformed)?;
+        let field = |field_offset: u64| {
+            offset
+                .checked_add(field_offset)
+                .ok_or(MapElfError::Malformed)
+        };
+        Ok(ProgramHeader {
+            p_type: read_u32(elf_bytes, offset)?,
+            p_flags: read_u32(elf_bytes, field(4)?)?,
+            p_offset: read_u64(elf_bytes, field(8)?)?,
+            p_vaddr: read_u64(elf_bytes, field(16)?)?,
+            p_filesz: read_u64(elf_bytes, field(32)?)?,
+            p_memsz: read_u64(elf_bytes, field(40)?)?,
+        })
+    }))
+}
+
+fn page_range(start: u64, len: u64) -> Result<Range<VirtAddr>, MapElfError> {
+    let end = start.checked_add(len).ok_or(MapElfError::Malformed)?;
+    let start = VirtAddr::try_new(start).map_err(|_| MapElfError::Malformed)?;
+    let end = VirtAddr::try_new(end).map_err(|_| MapElfError::Malformed)?;
+    Ok(start.align_down(PageSize::_4KiB.byte_len_u64())
+        ..end.align_up(PageSize::_4KiB.byte_len_u64()))
+}
+
+/// Returns the path of the interpreter (dynamic linker) requested by the ELF's `PT_INTERP` header, without the NUL terminator
+pub fn elf_interpreter(elf_bytes: &[u8]) -> Result<Option<&[u8]>, MapElfError> {
+    for header in program_headers(elf_bytes)? {
+        let header = header?;
+        if header.p_type == PT_INTERP {
+            let path = usize::try_from(header.p_offset)
+                .ok()
+                .and_then(|start| elf_bytes.get(start..)?.get(..header.p_filesz as usize))
+                .ok_or(MapElfError::Malformed)?;
+            return Ok(Some(path.strip_suffix(&[0]).unwrap_or(path)));
+        }
+    }
+    Ok(None)
+}
+
+impl ManagedL4PageTable {
+    /// Maps every `PT_LOAD` segment of an ELF at its virtual address plus `load_bias`.
+    /// Each page gets a new frame from `frame_allocator`, which is filled with the segment's contents from the file, and zeroes after that (such as for `.bss`).
+    /// Segments are writable if they have the `PF_W` flag, and executable if they have the `PF_X` flag. Segments with both flags are rejected.
+    ///
+    /// Segments are checked before anything is mapped. If mapping fails, the pages that were already mapped are unmapped,
+    /// and their frames and any page tables that become empty are given back to `frame_allocator`.
+    ///
+    /// # Safety
+    /// Same as [`ManagedL4PageTable::map_page`]
+    pub unsafe fn map_elf_segments(
+        &mut self,
+        elf_bytes: &[u8],
+        load_bias: u64,
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    ) -> Result<MappedElf, MapElfError> {
+        // Checks the ELF header before anything else is read
+        let headers = program_headers(elf_bytes)?;
+        let mut mapped_elf = MappedElf {
+            entry_point: VirtAddr::try_new(read_u64(elf_bytes, 0x18)?.wrapping_add(load_bias))
+                .map_err(|_| MapElfError::Malformed)?,
+            segments: [None; MAX_SEGMENTS],
+            relro: None,
+        };
+        let mut segment_count = 0;
+        for header in headers {
+            let header = header?;
+            let vaddr = header.p_vaddr.wrapping_add(load_bias);
+            match header.p_type {
+                PT_LOAD if header.p_memsz > 0 => {
+                    if header.p_filesz > header.p_memsz
+                        || header
+                            .p_offset
+                            .checked_add(header.p_filesz)
+                            .is_none_or(|end| end > elf_bytes.len() as u64)
+                    {
+                        return Err(MapElfError::Malformed);
+                    }
+                    let writable = header.p_flags & PF_W != 0;
+                    let executable = header.p_flags & PF_X != 0;
+                    if writable && executable {
+                        return Err(MapElfError::WriteAndExecute);
+                    }
+                    let pages = page_range(vaddr, header.p_memsz)?;
+                    if mapped_elf
+                        .segments()
+                        .any(|segment| pages.start < segment.end && segment.start < pages.end)
+                    {
+                        return Err(MapElfError::OverlappingSegments);
+                    }
+                    *mapped_elf
+                        .segments
+                        .get_mut(segment_count)
+                        .ok_or(MapElfError::TooManySegments)? = Some(MappedSegment {
+                        start: pages.start,
+                        end: pages.end,
+                        flags: ConfigurableFlags {
+                            writable,
+                            executable,
+                            pat_memory_type: PatMemoryType::WriteBack,
+                        },
+                    });
+                    segment_count += 1;
+                }
+                PT_GNU_RELRO => {
+                    // Like ld.so, only whole pages are made read-only
+                    let end = vaddr
+                        .checked_add(header.p_memsz)
+                        .ok_or(MapElfError::Malformed)?;
+                    let page_size = PageSize::_4KiB.byte_len_u64();
+                    mapped_elf.relro = Some(
+                        VirtAddr::try_new(vaddr)
+                            .map_err(|_| MapElfError::Malformed)?
+                            .align_down(page_size)
+                            ..VirtAddr::try_new(end)
+                                .map_err(|_| MapElfError::Malformed)?
+                                .align_down(page_size),
+                    );
+                }
+                _ => {}
+            }
+        }
+        let mut mapped_ends = mapped_elf
+            .segments
+            .map(|segment| segment.map_or(VirtAddr::zero(), |segment| segment.start));
+        let result = unsafe {
+            self.map_elf_pages(
+                elf_bytes,
+                load_bias,
+                &mapped_elf,
+                &mut mapped_ends,
+                frame_allocator,
+            )
+        };
+        if let Err(e) = result {
+            for (segment, end) in mapped_elf.segments().zip(mapped_ends) {
+                // The pages were just mapped, so unmapping them cannot fail
+                let _ = unsafe { self.vfree_pages(segment.start..end, frame_allocator) };
+            }
+            return Err(e);
+        }
+        Ok(mapped_elf)
+    }
+
+    /// Maps the pages of the segments checked by [`ManagedL4PageTable::map_elf_segments`].
+    /// `mapped_ends` is the end of the pages that were mapped so far in every segment.
+    unsafe fn map_elf_pages(
+        &mut self,
+        elf_bytes: &[u8],
+        load_bias: u64,
+        mapped_elf: &MappedElf,
+        mapped_ends: &mut [VirtAddr; MAX_SEGMENTS],
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    ) -> Result<(), MapElfError> {
+        let page_size = PageSize::_4KiB.byte_len_u64();
+        for header in program_headers(elf_bytes)? {
+            let header = header?;
+            if header.p_type != PT_LOAD || header.p_memsz == 0 {
+                continue;
+            }
+            let vaddr = header.p_vaddr.wrapping_add(load_bias);
+            let (index, segment) = mapped_elf
+                .segments()
+                .enumerate()
+                .find(|(_, segment)| {
+                    segment.start.as_u64() <= vaddr && vaddr < segment.end.as_u64()
+                })
+                .unwrap();
+            let file_bytes = &elf_bytes[header.p_offset as usize..][..header.p_filesz as usize];
+            let file_range = vaddr..vaddr + header.p_filesz;
+            let mut page_addr = segment.start;
+            while page_addr < segment.end {
+                let frame = frame_allocator
+                    .allocate_frame()
+                    .ok_or(MapElfError::FrameAllocationFailed)?;
+                let page_range = page_addr.as_u64()..page_addr.as_u64() + page_size;
+                let filled = unsafe {
+                    self.config.with_4kib_frame(frame, |bytes| {
+                        bytes.fill(0);
+                        let copy_start = page_range.start.max(file_range.start);
+                        let copy_end = page_range.end.min(file_range.end);
+                        if copy_start < copy_end {
+                            bytes[(copy_start - page_range.start) as usize..]
+                                [..(copy_end - copy_start) as usize]
+                                .copy_from_slice(
+                                    &file_bytes[(copy_start - file_range.start) as usize..]
+                                        [..(copy_end - copy_start) as usize],
+                                );
+                        }
+                    })
+                };
+                let result = match filled {
+                    Some(()) => unsafe {
+                        self.map_page(
+                            Page::new(page_addr, PageSize::_4KiB).unwrap(),
+                            Frame::new(frame.start_address(), PageSize::_4KiB).unwrap(),
+                            segment.flags,
+                            frame_allocator,
+                        )
+                    }
+                    .map_err(MapElfError::Map),
+                    None => Err(MapElfError::NotDirectlyMapped),
+                };
+                if let Err(e) = result {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                    return Err(e);
+                }
+                page_addr += page_size;
+                mapped_ends[index] = page_addr;
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes the RELRO pages of an ELF mapped with [`ManagedL4PageTable::map_elf_segments`] read-only.
+    /// Do this after applying relocations.
+    ///
+    /// # Safety
+    /// Nothing can be writing to the RELRO pages
+    pub unsafe fn protect_relro(&mut self, elf: &MappedElf) -> Result<(), UpdateFlagsError> {
+        let Some(relro) = elf.relro() else {
+            return Ok(());
+        };
+        let mut page_addr = relro.start;
+        while page_addr < relro.end {
+            let Some(segment) = elf
+                .segments()
+                .find(|segment| segment.start <= page_addr && page_addr < segment.end)
+            else {
+                page_addr += PageSize::_4KiB.byte_len_u64();
+                continue;
+            };
+            let flags = ConfigurableFlags {
+                writable: false,
+                ..segment.flags
+            };
+            unsafe { self.update_flags(Page::new(page_addr, PageSize::_4KiB).unwrap(), flags) }?;
+            page_addr += PageSize::_4KiB.byte_len_u64();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,322 @@
+use core::{
+    fmt::Debug,
+    ops::Range,
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, AtomicU32, AtomicU64, Ordering},
+};
+
+use x86_64::{PhysAddr, structures::paging::PhysFrame};
+
+use crate::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameFlags(u32);
+
+impl FrameFlags {
+    pub const EMPTY: Self = Self(0);
+    /// The frame must not be moved or reclaimed.
+    /// This is set while the frame's pin count is not 0, see [`FrameMeta::pin`].
+    pub const PINNED: Self = Self(1 << 0);
+    /// The frame was written to since it was last written back
+    pub const DIRTY: Self = Self(1 << 1);
+    /// The frame is used as a page table
+    pub const PAGE_TABLE: Self = Self(1 << 2);
+    /// The frame was released while it was pinned, and will be released when it is unpinned, see [`FrameDatabase::release`]
+    pub const RELEASE_DEFERRED: Self = Self(1 << 3);
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// Metadata of one 4 KiB frame.
+/// For a 2 MiB or 1 GiB frame, the metadata of its first 4 KiB frame is used.
+#[derive(Debug)]
+#[repr(C)]
+pub struct FrameMeta {
+    ref_count: AtomicU32,
+    map_count: AtomicU32,
+    flags: AtomicU32,
+    pin_count: AtomicU32,
+    owner: AtomicU64,
+}
+
+impl FrameMeta {
+    /// The number of references to the frame, which is managed by the owner of the frame
+    pub fn ref_count(&self) -> u32 {
+        self.ref_count.load(Ordering::Acquire)
+    }
+
+    /// Adds a reference and returns the new count
+    pub fn get(&self) -> u32 {
+        self.ref_count.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    /// Removes a reference and returns the new count. Panics if there were no references.
+    pub fn put(&self) -> u32 {
+        let previous = self.ref_count.fetch_sub(1, Ordering::AcqRel);
+        assert_ne!(previous, 0, "The frame had no references");
+        previous - 1
+    }
+
+    /// The number of pages that map the frame, which is updated by [`ManagedL4PageTable::map_page`] and [`ManagedL4PageTable::unmap_page`]
+    pub fn map_count(&self) -> u32 {
+        self.map_count.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn add_mapping(&self) {
+        self.map_count.fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub(crate) fn remove_mapping(&self) {
+        // Frames that were mapped before the database was attached can be unmapped
+        let _ = self
+            .map_count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                count.checked_sub(1)
+            });
+    }
+
+    /// The number of times the frame is pinned, such as by [`ManagedL4PageTable::pin_user_pages`]
+    pub fn pin_count(&self) -> u32 {
+        self.pin_count.load(Ordering::Acquire)
+    }
+
+    /// Pins the frame, which also sets [`FrameFlags::PINNED`]
+    pub fn pin(&self) {
+        if self.pin_count.fetch_add(1, Ordering::AcqRel) == 0 {
+            self.insert_flags(FrameFlags::PINNED);
+        }
+    }
+
+    /// Removes a pin, and clears [`FrameFlags::PINNED`] if it was the last one.
+    /// Returns `true` if the frame was released while it was pinned, and should be released now.
+    /// Panics if the frame was not pinned.
+    pub fn unpin(&self) -> bool {
+        let previous = self.pin_count.fetch_sub(1, Ordering::AcqRel);
+        assert_ne!(previous, 0, "The frame was not pinned");
+        if previous != 1 {
+            return false;
+        }
+        self.remove_flags(FrameFlags::PINNED);
+        self.take_deferred_release()
+    }
+
+    /// Clears [`FrameFlags::RELEASE_DEFERRED`], returning `true` if it was set
+    fn take_deferred_release(&self) -> bool {
+        let previous = self
+            .flags
+            .fetch_and(!FrameFlags::RELEASE_DEFERRED.0, Ordering::AcqRel);
+        FrameFlags(previous).contains(FrameFlags::RELEASE_DEFERRED)
+    }
+
+    pub fn flags(&self) -> FrameFlags {
+        FrameFlags(self.flags.load(Ordering::Acquire))
+    }
+
+    pub fn insert_flags(&self, flags: FrameFlags) {
+        self.flags.fetch_or(flags.0, Ordering::AcqRel);
+    }
+
+    pub fn remove_flags(&self, flags: FrameFlags) {
+        self.flags.fetch_and(!flags.0, Ordering::AcqRel);
+    }
+
+    /// An id of whatever owns the frame, such as a process. The meaning is up to the user of the database, and 0 means no owner.
+    pub fn owner(&self) -> u64 {
+        self.owner.load(Ordering::Acquire)
+    }
+
+    pub fn set_owner(&self, owner: u64) {
+        self.owner.store(owner, Ordering::Release);
+    }
+
+    /// Moves the references, flags and owner to the metadata of another frame, such as when the frame is migrated.
+    /// The pin count, and the flags that belong to the physical frame, are not moved.
+    pub(crate) fn move_to(&self, new: &FrameMeta) {
+        let kept = FrameFlags::PINNED
+            .union(FrameFlags::RELEASE_DEFERRED)
+            .union(FrameFlags::PAGE_TABLE);
+        new.ref_count
+            .store(self.ref_count.swap(0, Ordering::AcqRel), Ordering::Release);
+        let moved = self.flags.fetch_and(kept.0, Ordering::AcqRel) & !kept.0;
+        new.flags.fetch_or(moved, Ordering::AcqRel);
+        new.owner
+            .store(self.owner.swap(0, Ordering::AcqRel), Ordering::Release);
+    }
+}
+
+#[derive(Debug)]
+pub enum InitFrameDatabaseError {
+    AlreadyInitialized,
+    /// There are no RAM regions
+    NoMemory,
+    /// No RAM region is big enough to hold the metadata
+    NoSpace,
+    /// The metadata would not be in the direct map
+    NotDirectlyMapped,
+}
+
+/// Metadata for every 4 KiB frame of RAM, such as reference counts.
+/// The metadata is stored in physical memory, which is accessed through the direct map.
+///
+/// The database itself only holds the location of the metadata, so that it can be put in a `static`.
+pub struct FrameDatabase {
+    base: AtomicU64,
+    frame_count: AtomicU64,
+    entries: AtomicPtr<FrameMeta>,
+}
+
+impl FrameDatabase {
+    pub const fn new() -> Self {
+        Self {
+            base: AtomicU64::new(0),
+            frame_count: AtomicU64::new(0),
+            entries: AtomicPtr::new(null_mut()),
+        }
+    }
+
+    /// Creates metadata for every frame from the start of the first [`PhysRegionKind::Ram`] region to the end of the last one.
+    /// The metadata is stored at the start of the first RAM region that is big enough, and the memory it uses is returned.
+    /// That memory must not be given to a frame allocator.
+    ///
+    /// # Safety
+    /// The RAM regions must not be used by anything else yet
+    pub unsafe fn init(
+        &self,
+        config: &PagingConfig,
+        regions: impl IntoIterator<Item = PhysRegion> + Clone,
+    ) -> Result<Range<PhysAddr>, InitFrameDatabaseError> {
+        if !self.entries.load(Ordering::Acquire).is_null() {
+            return Err(InitFrameDatabaseError::AlreadyInitialized);
+        }
+        let ram_ranges = || {
+            regions
+                .clone()
+                .into_iter()
+                .filter(|region| region.kind == PhysRegionKind::Ram)
+                .map(|region| {
+                    region.range.start.align_up(PageSize::_4KiB.byte_len_u64())
+                        ..region.range.end.align_down(PageSize::_4KiB.byte_len_u64())
+                })
+                .filter(|range| range.start < range.end)
+        };
+        let base = ram_ranges()
+            .map(|range| range.start)
+            .min()
+            .ok_or(InitFrameDatabaseError::NoMemory)?;
+        let end = ram_ranges().map(|range| range.end).max().unwrap();
+        let frame_count = (end - base) / PageSize::_4KiB.byte_len_u64();
+        let len = (frame_count * size_of::<FrameMeta>() as u64)
+            .next_multiple_of(PageSize::_4KiB.byte_len_u64());
+        let storage = ram_ranges()
+            .find(|range| range.end - range.start >= len)
+            .ok_or(InitFrameDatabaseError::NoSpace)?
+            .start;
+        let mut bytes = config
+            .phys_slice::<u8>(storage, len as usize)
+            .ok_or(InitFrameDatabaseError::NotDirectlyMapped)?;
+        // All zeroes is a valid `FrameMeta` with no references, mappings, flags, or owner
+        unsafe { bytes.as_slice_mut() }.fill(0);
+        let entries = config.phys_ptr::<FrameMeta>(storage).unwrap();
+        self.base.store(base.as_u64(), Ordering::Relaxed);
+        self.frame_count.store(frame_count, Ordering::Relaxed);
+        self.entries.store(entries.as_ptr(), Ordering::Release);
+        Ok(storage..storage + len)
+    }
+
+    /// Returns the metadata of the 4 KiB frame containing `addr`, or `None` if the database was not initialized or the frame is not covered by it
+    pub fn get(&self, addr: PhysAddr) -> Option<&FrameMeta> {
+        let entries = self.entries.load(Ordering::Acquire);
+        if entries.is_null() {
+            return None;
+        }
+        let index = addr
+            .as_u64()
+            .checked_sub(self.base.load(Ordering::Relaxed))?
+            / PageSize::_4KiB.byte_len_u64();
+        if index >= self.frame_count.load(Ordering::Relaxed) {
+            return None;
+        }
+        Some(unsafe { &*entries.add(index as usize) })
+    }
+
+    /// The metadata of a frame of any size
+    pub fn frame(&self, frame: Frame) -> Option<&FrameMeta> {
+        self.get(frame.start_addr())
+    }
+
+    /// Calls `release` (such as to give the frame back to the frame allocator) now, unless the frame is pinned.
+    /// If it is pinned, the frame is marked with [`FrameFlags::RELEASE_DEFERRED`], and whatever removes the last pin must release it.
+    /// Use this instead of freeing frames directly after unmapping them with [`ManagedL4PageTable::unmap_page`].
+    pub fn release(&self, frame: Frame, release: impl FnOnce(Frame)) {
+        let Some(meta) = self.frame(frame) else {
+            release(frame);
+            return;
+        };
+        meta.insert_flags(FrameFlags::RELEASE_DEFERRED);
+        // If the last pin was removed at the same time, only one of this and `unpin` sees the flag
+        if meta.pin_count() == 0 && meta.take_deferred_release() {
+            release(frame);
+        }
+    }
+}
+
+impl Default for FrameDatabase {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debug for FrameDatabase {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("FrameDatabase")
+            .field("base", &PhysAddr::new(self.base.load(Ordering::Relaxed)))
+            .field("frame_count", &self.frame_count.load(Ordering::Relaxed))
+            .finish_non_exhaustive()
+    }
+}
+
+impl PagingConfig {
+    /// Makes [`ManagedL4PageTable::map_page`] and [`ManagedL4PageTable::unmap_page`] update the map count of frames in `database`,
+    /// and marks page tables created by this crate with [`FrameFlags::PAGE_TABLE`].
+    pub fn with_frame_database(mut self, database: &'static FrameDatabase) -> Self {
+        self.frame_database = Some(database);
+        self
+    }
+
+    /// Sets or clears [`FrameFlags::PAGE_TABLE`] of a frame, if it is in the attached [`FrameDatabase`]
+    pub(crate) fn mark_page_table(&self, frame: PhysFrame, is_page_table: bool) {
+        let Some(meta) = self
+            .frame_database
+            .and_then(|database| database.get(frame.start_address()))
+        else {
+            return;
+        };
+        if is_page_table {
+            meta.insert_flags(FrameFlags::PAGE_TABLE);
+        } else {
+            meta.remove_flags(FrameFlags::PAGE_TABLE);
+        }
+    }
+
+    /// Frees a frame that was unmapped with [`FrameDatabase::release`] if a [`FrameDatabase`] is attached, so that pinned frames are not freed until they are unpinned
+    pub(crate) fn release_frame(&self, frame: Frame, release: impl FnOnce(Frame)) {
+        match self.frame_database {
+            Some(database) => database.release(frame, release),
+            None => release(frame),
+        }
+    }
+}
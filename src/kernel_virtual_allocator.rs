@@ -0,0 +1,111 @@
+use core::{fmt::Debug, ops::Range};
+
+use x86_64::VirtAddr;
+
+use crate::*;
+
+/// Hands out virtual address ranges from a window in the higher half, such as for [`ManagedL4PageTable::vmalloc`].
+///
+/// Free ranges are kept in a fixed size array, so that the allocator can be put in a `static`.
+/// Freed ranges are merged with the free ranges next to them.
+/// Every allocated range keeps a slot of the array reserved, so that freeing it never runs out of space.
+pub struct KernelVirtualAllocator<const N: usize> {
+    free_ranges: SpinLock<FreeRanges<N>>,
+}
+
+struct FreeRanges<const N: usize> {
+    ranges: [Option<Range<VirtAddr>>; N],
+    /// The number of allocated ranges, which each reserve a slot in `ranges` for when they are freed
+    allocated: usize,
+}
+
+impl<const N: usize> KernelVirtualAllocator<N> {
+    /// `window` must be 4 KiB aligned and in the higher half, and must not be used for anything else
+    pub const fn new(window: Range<VirtAddr>) -> Self {
+        assert!(N > 0);
+        assert!(
+            window.start.as_u64() >= 0xffff_8000_0000_0000
+                && window.start.as_u64() % 0x1000 == 0
+                && window.end.as_u64() % 0x1000 == 0
+        );
+        let mut ranges = [const { None }; N];
+        ranges[0] = Some(window);
+        Self {
+            free_ranges: SpinLock::new(FreeRanges {
+                ranges,
+                allocated: 0,
+            }),
+        }
+    }
+
+    /// Allocates `len` bytes, rounded up to 4 KiB, starting at a multiple of `align`.
+    /// Returns `None` if `len` is 0 or too big, if `align` is not a power of two, if there is no free range that is big enough,
+    /// or if there is no space to keep track of the free range being split and of the allocated range when it is freed.
+    pub fn allocate(&self, len: u64, align: u64) -> Option<Range<VirtAddr>> {
+        if len == 0 || !align.is_power_of_two() {
+            return None;
+        }
+        let len = len.checked_next_multiple_of(PageSize::_4KiB.byte_len_u64())?;
+        let align = align.max(PageSize::_4KiB.byte_len_u64());
+        let mut free_ranges = self.free_ranges.lock();
+        let FreeRanges {
+            ranges: free_ranges,
+            allocated,
+        } = &mut *free_ranges;
+        let empty_slots = free_ranges.iter().filter(|range| range.is_none()).count();
+        let (index, start) = free_ranges.iter().enumerate().find_map(|(index, range)| {
+            let range = range.as_ref()?;
+            let start = range.start.as_u64().checked_next_multiple_of(align)?;
+            (start.checked_add(len)? <= range.end.as_u64()).then(|| (index, VirtAddr::new(start)))
+        })?;
+        let range = free_ranges[index].take().unwrap();
+        let end = start + len;
+        let leftovers = [range.start..start, end..range.end];
+        let needed_slots = leftovers.iter().filter(|range| !range.is_empty()).count();
+        // The slot of the split range can be reused, and one slot stays reserved for every allocated range, including this one
+        if needed_slots + *allocated > empty_slots {
+            free_ranges[index] = Some(range);
+            return None;
+        }
+        for leftover in leftovers.into_iter().filter(|range| !range.is_empty()) {
+            *free_ranges
+                .iter_mut()
+                .find(|range| range.is_none())
+                .unwrap() = Some(leftover);
+        }
+        *allocated += 1;
+        Some(start..end)
+    }
+
+    /// Frees a range returned by [`KernelVirtualAllocator::allocate`].
+    /// This cannot run out of space, since a slot was reserved for the range when it was allocated.
+    pub fn free(&self, range: Range<VirtAddr>) {
+        let mut free_ranges = self.free_ranges.lock();
+        let FreeRanges {
+            ranges: free_ranges,
+            allocated,
+        } = &mut *free_ranges;
+        *allocated -= 1;
+        let mut merged = range;
+        for slot in free_ranges.iter_mut() {
+            if let Some(free_range) = slot
+                && (free_range.end == merged.start || merged.end == free_range.start)
+            {
+                merged = free_range.start.min(merged.start)..free_range.end.max(merged.end);
+                *slot = None;
+            }
+        }
+        *free_ranges
+            .iter_mut()
+            .find(|range| range.is_none())
+            .unwrap() = Some(merged);
+    }
+}
+
+impl<const N: usize> Debug for KernelVirtualAllocator<N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("KernelVirtualAllocator")
+            .field("capacity", &N)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,178 @@
+use core::{
+    cell::UnsafeCell,
+    fmt::Debug,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use x86_64::{
+    PhysAddr, VirtAddr,
+    instructions::tlb::flush,
+    structures::paging::{PageTable, PageTableFlags, PhysFrame},
+};
+
+use crate::*;
+
+const SLOT_COUNT: usize = 512;
+
+/// A 2 MiB region in the higher half where frames are temporarily mapped while they are accessed, for when there is no direct map.
+/// The region has 512 4 KiB slots, which are split evenly between CPUs.
+///
+/// The L3, L2, and L1 tables of the region are part of this struct, so it should be put in a `static`.
+#[repr(C, align(4096))]
+pub struct KmapWindow {
+    // These must be the first fields, so that they are at the physical address of the struct
+    l3: UnsafeCell<PageTable>,
+    l2: UnsafeCell<PageTable>,
+    l1: UnsafeCell<PageTable>,
+    base: VirtAddr,
+    slots_per_cpu: usize,
+    cpu_id: fn() -> usize,
+    in_use: [AtomicBool; SLOT_COUNT],
+}
+
+unsafe impl Sync for KmapWindow {}
+
+#[derive(Debug)]
+pub enum InstallKmapWindowError {
+    /// The L4 entry covering the window is already used by something else
+    L4EntryUsed,
+}
+
+impl KmapWindow {
+    /// # Safety
+    /// - `base` must be 2 MiB aligned and in the higher half, and nothing else can be mapped in the 512 GiB region covered by its L4 entry
+    /// - `cpu_id` must return a different number for every CPU, which is less than `512 / slots_per_cpu`
+    pub const unsafe fn new(base: VirtAddr, slots_per_cpu: usize, cpu_id: fn() -> usize) -> Self {
+        // A page table walk needs to access a parent and child table at the same time
+        assert!(slots_per_cpu >= 2 && slots_per_cpu <= SLOT_COUNT);
+        Self {
+            l3: UnsafeCell::new(PageTable::new()),
+            l2: UnsafeCell::new(PageTable::new()),
+            l1: UnsafeCell::new(PageTable::new()),
+            base,
+            slots_per_cpu,
+            cpu_id,
+            in_use: [const { AtomicBool::new(false) }; SLOT_COUNT],
+        }
+    }
+
+    pub fn base(&self) -> VirtAddr {
+        self.base
+    }
+
+    /// Points a L4 table to this window's page tables.
+    /// The window must be installed in the active L4 table before [`KmapWindow::map`] can be used,
+    /// so this should be used to install it in the L4 table that is active at boot.
+    /// [`PagingConfig::new_kernel`] installs it automatically.
+    ///
+    /// # Safety
+    /// `phys_addr` must be the physical address of `self`
+    pub unsafe fn install(
+        &self,
+        l4_table: &mut PageTable,
+        phys_addr: PhysAddr,
+    ) -> Result<(), InstallKmapWindowError> {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let table_frame =
+            |index: u64| PhysFrame::from_start_address(phys_addr + index * 0x1000).unwrap();
+        let l4_entry = &mut l4_table[self.base.p4_index()];
+        if !l4_entry.is_unused() && l4_entry.addr() != phys_addr {
+            return Err(InstallKmapWindowError::L4EntryUsed);
+        }
+        l4_entry.set_frame(table_frame(0), flags);
+        let l3 = unsafe { &mut *self.l3.get() };
+        l3[self.base.p3_index()].set_frame(table_frame(1), flags);
+        let l2 = unsafe { &mut *self.l2.get() };
+        l2[self.base.p2_index()].set_frame(table_frame(2), flags);
+        Ok(())
+    }
+
+    /// Maps a frame into one of this CPU's slots until the guard is dropped.
+    /// Panics if all of this CPU's slots are used.
+    pub fn map(&'static self, frame: PhysFrame) -> KmapGuard {
+        let cpu_slots = {
+            let start = (self.cpu_id)() * self.slots_per_cpu;
+            start..start + self.slots_per_cpu
+        };
+        let slot = cpu_slots
+            .clone()
+            .find(|slot| {
+                self.in_use[*slot]
+                    .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            })
+            .unwrap_or_else(|| panic!("All kmap slots of this CPU ({cpu_slots:?}) are used"));
+        // Slots are only used by one CPU, so nothing else accesses this entry
+        let l1 = unsafe { &mut *self.l1.get() };
+        l1[slot].set_frame(
+            frame,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        );
+        let guard = KmapGuard { window: self, slot };
+        flush(guard.virt_addr());
+        guard
+    }
+}
+
+impl Debug for KmapWindow {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("KmapWindow")
+            .field("base", &self.base)
+            .field("slots_per_cpu", &self.slots_per_cpu)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A frame that is mapped in a [`KmapWindow`] slot. The slot is unmapped with `invlpg` when this is dropped.
+/// This must be dropped on the same CPU that it was created on.
+#[derive(Debug)]
+pub struct KmapGuard {
+    window: &'static KmapWindow,
+    slot: usize,
+}
+
+impl KmapGuard {
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.window.base + self.slot as u64 * 0x1000
+    }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.virt_addr().as_mut_ptr()
+    }
+}
+
+impl Drop for KmapGuard {
+    fn drop(&mut self) {
+        let l1 = unsafe { &mut *self.window.l1.get() };
+        l1[self.slot].set_unused();
+        flush(self.virt_addr());
+        self.window.in_use[self.slot].store(false, Ordering::Release);
+    }
+}
+
+impl PagingConfig {
+    /// Access page tables and frames by temporarily mapping them in a [`KmapWindow`] instead of using a direct map.
+    /// The window is installed automatically in L4 tables created with [`PagingConfig::new_kernel`].
+    ///
+    /// Features that need a direct map, such as [`PagingConfig::phys_ptr`], are not available.
+    ///
+    /// # Safety
+    /// - `window_phys_addr` must be the physical address of `window`
+    /// - The window must be installed in the L4 table that is active when calling this (see [`KmapWindow::install`])
+    pub unsafe fn new_kmap(
+        pat: ManagedPat,
+        window: &'static KmapWindow,
+        window_phys_addr: PhysAddr,
+    ) -> Self {
+        Self {
+            pat,
+            table_access: TableAccess::Kmap {
+                window,
+                window_phys_addr,
+            },
+            mem_types: None,
+            frame_database: None,
+            reverse_map: None,
+        }
+    }
+}
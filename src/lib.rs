@@ -5,23 +5,53 @@
 //! Get started by constructing a [`PagingConfig`],
 #![no_std]
 use addr_translation::*;
+pub use buddy_allocator::*;
+use cache::*;
+pub use compaction::*;
+#[cfg(feature = "elf")]
+pub use elf::*;
 pub use frame::*;
+pub use frame_database::*;
+pub use kernel_virtual_allocator::*;
+pub use kmap::*;
 pub use managed_l4_table::*;
 pub use managed_pat::*;
 pub use max_page_size::*;
+pub use mem_type_database::*;
 pub use owned_4kib_frame::*;
+pub use owned_frame::*;
 pub use page::*;
 pub use page_size::*;
 pub use paging_config::*;
+pub use phys_ptr::*;
+pub use reverse_map::*;
+pub use spin_lock::*;
+pub use static_table_pool::*;
+use table_access::*;
 pub use virtual_offset::*;
 
 mod addr_translation;
+mod buddy_allocator;
+mod cache;
+mod compaction;
+#[cfg(feature = "elf")]
+mod elf;
 mod frame;
+mod frame_database;
+mod kernel_virtual_allocator;
+mod kmap;
 mod managed_l4_table;
 mod managed_pat;
 mod max_page_size;
+mod mem_type_database;
 mod owned_4kib_frame;
+mod owned_frame;
 mod page;
 mod page_size;
 mod paging_config;
+mod phys_ptr;
+mod reverse_map;
+mod spin_lock;
+mod static_table_pool;
+mod table_access;
 mod virtual_offset;
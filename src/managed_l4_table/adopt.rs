@@ -0,0 +1,284 @@
+use core::ops::Range;
+
+use x86_64::{
+    VirtAddr,
+    registers::control::Cr3,
+    structures::paging::{
+        FrameAllocator, PageTableFlags, PageTableIndex, PhysFrame, Size4KiB,
+        page_table::PageTableEntry,
+    },
+};
+
+use crate::*;
+
+use super::{ManagedL4PageTable, PageTableLevel, SOFTWARE_FLAGS};
+
+/// Kept small since the report is returned by value, and there might not be a heap yet
+const MAX_REPORTED_ISSUES: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdoptIssueKind {
+    /// A page in the higher half has the USER_ACCESSIBLE flag
+    UserAccessible,
+    /// A page has flags which ez_paging uses to keep track of things
+    SoftwareFlags,
+    /// A L4 entry has the HUGE_PAGE flag
+    HugePageInL4,
+    /// A page table entry is not writable or not executable, so ez_paging would not be able to map writable or executable pages in it
+    RestrictiveTableFlags,
+    /// A 1 GiB page is mapped, but this CPU does not support 1 GiB pages
+    PageSizeNotSupported,
+    /// A page table is not in the direct map
+    NotAccessible,
+    /// An entry points to a page table that contains it, such as a recursive L4 entry, which would be copied forever
+    SelfReference,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AdoptIssue {
+    /// The start of the region mapped by the entry
+    pub addr: VirtAddr,
+    /// The level of the page table containing the entry
+    pub level: PageTableLevel,
+    pub kind: AdoptIssueKind,
+}
+
+/// Entries in the active page tables that ez_paging cannot manage
+#[derive(Debug, Clone)]
+pub struct AdoptReport {
+    issues: [Option<AdoptIssue>; MAX_REPORTED_ISSUES],
+    count: usize,
+}
+
+impl AdoptReport {
+    fn new() -> Self {
+        Self {
+            issues: [None; MAX_REPORTED_ISSUES],
+            count: 0,
+        }
+    }
+
+    fn push(&mut self, issue: AdoptIssue) {
+        if let Some(slot) = self.issues.get_mut(self.count) {
+            *slot = Some(issue);
+        }
+        self.count += 1;
+    }
+
+    /// The first issues that were found
+    pub fn issues(&self) -> impl Iterator<Item = &AdoptIssue> {
+        self.issues.iter().flatten()
+    }
+
+    /// The number of issues that were found, which can be more than the number of issues that are stored
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+#[derive(Debug)]
+pub enum AdoptError {
+    /// Page tables cannot be copied in recursive mode
+    Recursive,
+    Unmanageable(AdoptReport),
+    FrameAllocationFailed,
+    /// A frame from the frame allocator is not in the direct map
+    NotDirectlyMapped,
+}
+
+fn index_shift(level: PageTableLevel) -> u64 {
+    match level {
+        PageTableLevel::L1 => 12,
+        PageTableLevel::L2 => 21,
+        PageTableLevel::L3 => 30,
+        PageTableLevel::L4 => 39,
+    }
+}
+
+fn indexes(level: PageTableLevel) -> Range<usize> {
+    match level {
+        PageTableLevel::L4 => 256..512,
+        _ => 0..512,
+    }
+}
+
+/// Tables are only accessed one entry at a time, so that at most 2 tables are accessed at once in kmap mode
+fn read_entry(config: &PagingConfig, table: PhysFrame, index: usize) -> Option<PageTableEntry> {
+    let mut table_ptr = config.new_table_ptr(table)?;
+    Some(unsafe { table_ptr.ptr.as_mut() }[index].clone())
+}
+
+fn set_entry_frame(
+    config: &PagingConfig,
+    table: PhysFrame,
+    index: usize,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+) -> Option<()> {
+    let mut table_ptr = config.new_table_ptr(table)?;
+    let table = unsafe { table_ptr.ptr.as_mut() };
+    table[index].set_frame(frame, flags);
+    Some(())
+}
+
+fn is_reserved(config: &PagingConfig, level: PageTableLevel, index: usize) -> bool {
+    level == PageTableLevel::L4 && config.is_reserved_l4_index(PageTableIndex::new(index as u16))
+}
+
+/// `tables` are the page tables from the L4 table to the table being validated
+fn validate_table(
+    config: &PagingConfig,
+    tables: &[PhysFrame],
+    level: PageTableLevel,
+    start_addr: VirtAddr,
+    report: &mut AdoptReport,
+) {
+    let table = *tables.last().unwrap();
+    for index in indexes(level) {
+        let addr =
+            VirtAddr::new_truncate(start_addr.as_u64() | ((index as u64) << index_shift(level)));
+        if is_reserved(config, level, index) {
+            continue;
+        }
+        let mut issue = |kind| report.push(AdoptIssue { addr, level, kind });
+        let Some(entry) = read_entry(config, table, index) else {
+            issue(AdoptIssueKind::NotAccessible);
+            return;
+        };
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        if level == PageTableLevel::L1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            let Some(page_size) = level.target_frame_size() else {
+                issue(AdoptIssueKind::HugePageInL4);
+                continue;
+            };
+            if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+                issue(AdoptIssueKind::UserAccessible);
+            }
+            if flags.intersects(SOFTWARE_FLAGS) {
+                issue(AdoptIssueKind::SoftwareFlags);
+            }
+            if page_size > max_page_size() {
+                issue(AdoptIssueKind::PageSizeNotSupported);
+            }
+        } else {
+            if !flags.contains(PageTableFlags::WRITABLE)
+                || flags.contains(PageTableFlags::NO_EXECUTE)
+            {
+                issue(AdoptIssueKind::RestrictiveTableFlags);
+            }
+            let sub_table = PhysFrame::containing_address(entry.addr());
+            if tables.contains(&sub_table) {
+                issue(AdoptIssueKind::SelfReference);
+                continue;
+            }
+            // There are at most 4 levels of page tables
+            let mut sub_tables = [sub_table; 4];
+            sub_tables[..tables.len()].copy_from_slice(tables);
+            validate_table(
+                config,
+                &sub_tables[..=tables.len()],
+                level.sub_level().unwrap(),
+                addr,
+                report,
+            );
+        }
+    }
+}
+
+/// Copies a page table and all page tables it points to into new frames
+fn copy_table(
+    config: &PagingConfig,
+    table: PhysFrame,
+    level: PageTableLevel,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<PhysFrame, AdoptError> {
+    let new_table = frame_allocator
+        .allocate_frame()
+        .ok_or(AdoptError::FrameAllocationFailed)?;
+    config.mark_page_table(new_table, true);
+    let as_frame = |frame: PhysFrame| Frame::new(frame.start_address(), PageSize::_4KiB).unwrap();
+    unsafe { config.copy_frame(as_frame(table), as_frame(new_table)) }
+        .map_err(|_| AdoptError::NotDirectlyMapped)?;
+    if let Some(sub_level) = level.sub_level() {
+        for index in 0..512 {
+            let entry =
+                read_entry(config, new_table, index).ok_or(AdoptError::NotDirectlyMapped)?;
+            let flags = entry.flags();
+            if flags.contains(PageTableFlags::PRESENT) && !flags.contains(PageTableFlags::HUGE_PAGE)
+            {
+                let new_sub_table = copy_table(
+                    config,
+                    PhysFrame::containing_address(entry.addr()),
+                    sub_level,
+                    frame_allocator,
+                )?;
+                set_entry_frame(config, new_table, index, new_sub_table, flags)
+                    .ok_or(AdoptError::NotDirectlyMapped)?;
+            }
+        }
+    }
+    Ok(new_table)
+}
+
+impl PagingConfig {
+    /// Creates a kernel page table with a copy of the higher half of the active page tables, which are usually made by the bootloader.
+    /// Every page table in the higher half is copied into frames from `frame_allocator`, so the bootloader's page tables can be freed after switching to the new page table.
+    /// The lower half, which often has identity maps, is not copied.
+    ///
+    /// The higher half is checked for entries that ez_paging cannot manage before anything is copied, and they are returned in an [`AdoptReport`].
+    /// This includes recursive entries, which point back to the L4 table or another table above them.
+    /// Frames that were allocated before an error are not freed.
+    /// Not available in recursive mode.
+    ///
+    /// # Safety
+    /// The active page tables must not be changed while they are being copied
+    pub unsafe fn adopt_current_kernel(
+        self,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<ManagedL4PageTable, AdoptError> {
+        if self.reserved_l4_indexes().is_some() {
+            return Err(AdoptError::Recursive);
+        }
+        let (active_frame, _) = Cr3::read();
+        let mut report = AdoptReport::new();
+        validate_table(
+            &self,
+            &[active_frame],
+            PageTableLevel::L4,
+            VirtAddr::zero(),
+            &mut report,
+        );
+        if report.count() > 0 {
+            return Err(AdoptError::Unmanageable(report));
+        }
+        let l4_frame = frame_allocator
+            .allocate_frame()
+            .ok_or(AdoptError::FrameAllocationFailed)?;
+        if self.new_table_ptr(l4_frame).is_none() {
+            return Err(AdoptError::NotDirectlyMapped);
+        }
+        let page_table = self.new_kernel(unsafe { Owned4KibFrame::new(l4_frame) });
+        for index in indexes(PageTableLevel::L4) {
+            if is_reserved(&self, PageTableLevel::L4, index) {
+                continue;
+            }
+            let entry =
+                read_entry(&self, active_frame, index).ok_or(AdoptError::NotDirectlyMapped)?;
+            if !entry.flags().contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+            let l3_frame = copy_table(
+                &self,
+                PhysFrame::containing_address(entry.addr()),
+                PageTableLevel::L3,
+                frame_allocator,
+            )?;
+            set_entry_frame(&self, l4_frame, index, l3_frame, entry.flags())
+                .ok_or(AdoptError::NotDirectlyMapped)?;
+        }
+        Ok(page_table)
+    }
+}
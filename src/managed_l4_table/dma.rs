@@ -0,0 +1,180 @@
+use core::ops::Range;
+
+use x86_64::{
+    PhysAddr, VirtAddr, registers::model_specific::PatMemoryType, structures::paging::PhysFrame,
+};
+
+use crate::*;
+
+use super::{ManagedL4PageTable, MemTypeError};
+
+#[derive(Debug)]
+pub enum AllocDmaError {
+    /// There is no free contiguous physical memory that is big enough below the limit
+    FrameAllocationFailed,
+    /// The [`KernelVirtualAllocator`] has no free range that is big enough
+    NoVirtualSpace,
+    MemType(MemTypeError),
+    Map(MapPageError),
+}
+
+/// Physically contiguous memory allocated with [`ManagedL4PageTable::alloc_dma`], for devices to access with DMA.
+/// The memory is unmapped and freed when this is dropped, with [`FrameDatabase::release`] if a [`FrameDatabase`] is attached.
+#[derive(Debug)]
+pub struct DmaBuffer<const N: usize> {
+    config: PagingConfig,
+    l4_frame: PhysFrame,
+    virtual_allocator: &'static KernelVirtualAllocator<N>,
+    frame_allocator: &'static SpinLock<BuddyAllocator>,
+    phys_addr: PhysAddr,
+    memory_type: PatMemoryType,
+    /// Can be longer than the buffer
+    virt_range: Range<VirtAddr>,
+    /// The end of the pages that were mapped so far
+    mapped_end: VirtAddr,
+    len: u64,
+}
+
+impl<const N: usize> DmaBuffer<N> {
+    /// The address to give to the device
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys_addr
+    }
+
+    /// The physical range that is mapped, which can be longer than the buffer
+    fn phys_range(&self) -> Range<PhysAddr> {
+        self.phys_addr..self.phys_addr + (self.virt_range.end - self.virt_range.start)
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.virt_range.start
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.virt_addr().as_mut_ptr(), self.len as usize) }
+    }
+}
+
+impl<const N: usize> Drop for DmaBuffer<N> {
+    fn drop(&mut self) {
+        let mut table = unsafe {
+            ManagedL4PageTable::from_mapping(
+                self.config,
+                Mapping {
+                    l4_frame: self.l4_frame,
+                    page: Page::new(self.virt_range.start, PageSize::_4KiB).unwrap(),
+                },
+            )
+        };
+        let mut addr = self.virt_range.start;
+        while addr < self.mapped_end {
+            // Every page was mapped by alloc_dma
+            let _ =
+                unsafe { table.unmap_page_untracked(Page::new(addr, PageSize::_4KiB).unwrap()) };
+            addr += PageSize::_4KiB.byte_len_u64();
+        }
+        table.untrack_mem_type_range(self.phys_range(), self.memory_type);
+        self.virtual_allocator.free(self.virt_range.clone());
+        // Frame by frame, since pinned frames are released on their own when they are unpinned
+        let mut frame_allocator = self.frame_allocator.lock();
+        let mut phys_addr = self.phys_addr;
+        while phys_addr < self.phys_range().end {
+            let frame = Frame::new(phys_addr, PageSize::_4KiB).unwrap();
+            self.config
+                .release_frame(frame, |frame| unsafe { frame_allocator.deallocate(frame) });
+            phys_addr += PageSize::_4KiB.byte_len_u64();
+        }
+    }
+}
+
+impl ManagedL4PageTable {
+    /// Allocates `len` bytes of physically contiguous memory from `frame_allocator`, starting at a multiple of `align` (a power of two) and ending at or below `max_phys_addr`,
+    /// and maps it in a range from `virtual_allocator` with `memory_type`, writable and not executable.
+    /// Page tables are also allocated from `frame_allocator`.
+    /// The memory is zeroed.
+    ///
+    /// If a [`MemTypeDatabase`] is attached to the [`PagingConfig`], the whole buffer is reserved with `memory_type` as one reservation,
+    /// and the buffer's alias in the direct map is handled according to the [`MemTypeConflictPolicy`]. Without one, the direct map still maps the memory as write-back.
+    /// If `memory_type` is not write-back, the buffer's cache lines are flushed, since the allocator could have written to it through the direct map.
+    ///
+    /// This must be a kernel page table.
+    ///
+    /// # Safety
+    /// Same as [`ManagedL4PageTable::map_page`].
+    /// The returned [`DmaBuffer`] unmaps the memory in this page table when it is dropped, so this page table must still exist then and must not be accessed at the same time.
+    /// It must not be dropped while a device is still accessing it, and `frame_allocator` must not be locked on the CPU that drops it.
+    pub unsafe fn alloc_dma<const N: usize>(
+        &mut self,
+        virtual_allocator: &'static KernelVirtualAllocator<N>,
+        frame_allocator: &'static SpinLock<BuddyAllocator>,
+        len: u64,
+        align: u64,
+        memory_type: PatMemoryType,
+        max_phys_addr: PhysAddr,
+    ) -> Result<DmaBuffer<N>, AllocDmaError> {
+        let phys_addr = frame_allocator
+            .lock()
+            .allocate_contiguous(len, align, max_phys_addr)
+            .ok_or(AllocDmaError::FrameAllocationFailed)?;
+        let Some(virt_range) =
+            virtual_allocator.allocate(len.max(1), PageSize::_4KiB.byte_len_u64())
+        else {
+            unsafe { frame_allocator.lock().deallocate_contiguous(phys_addr, len) };
+            return Err(AllocDmaError::NoVirtualSpace);
+        };
+        let phys_range = phys_addr..phys_addr + (virt_range.end - virt_range.start);
+        let result = self.track_mem_type_range(
+            phys_range.clone(),
+            memory_type,
+            &mut *frame_allocator.lock(),
+        );
+        if let Err(e) = result {
+            virtual_allocator.free(virt_range);
+            unsafe { frame_allocator.lock().deallocate_contiguous(phys_addr, len) };
+            return Err(AllocDmaError::MemType(e));
+        }
+        if memory_type != PatMemoryType::WriteBack {
+            flush_phys_range(phys_addr, phys_range.end - phys_range.start, &self.config);
+        }
+        let mut buffer = DmaBuffer {
+            config: self.config,
+            l4_frame: self.frame.0,
+            virtual_allocator,
+            frame_allocator,
+            phys_addr,
+            memory_type,
+            virt_range: virt_range.clone(),
+            mapped_end: virt_range.start,
+            len,
+        };
+        let flags = ConfigurableFlags {
+            writable: true,
+            executable: false,
+            pat_memory_type: memory_type,
+        };
+        while buffer.mapped_end < virt_range.end {
+            let offset = buffer.mapped_end - virt_range.start;
+            unsafe {
+                self.map_page_untracked(
+                    Page::new(buffer.mapped_end, PageSize::_4KiB).unwrap(),
+                    Frame::new(phys_addr + offset, PageSize::_4KiB).unwrap(),
+                    flags,
+                    &mut *frame_allocator.lock(),
+                )
+            }
+            // Dropping the buffer unmaps what was mapped so far and frees everything
+            .map_err(AllocDmaError::Map)?;
+            buffer.mapped_end += PageSize::_4KiB.byte_len_u64();
+        }
+        buffer.as_slice_mut().fill(0);
+        Ok(buffer)
+    }
+}
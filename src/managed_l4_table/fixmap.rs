@@ -0,0 +1,176 @@
+use core::{
+    cell::UnsafeCell,
+    fmt::Debug,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use x86_64::{
+    PhysAddr, VirtAddr,
+    registers::model_specific::PatMemoryType,
+    structures::paging::{FrameAllocator, PageTable, PhysFrame, Size4KiB},
+};
+
+use crate::*;
+
+use super::{L4Type, ManagedL4PageTable, MapPageError, NoFrameAllocator, get_or_create_page_table};
+
+/// Pages with fixed virtual addresses in the top 2 MiB of the address space.
+/// They are meant for devices that need to be accessed early in boot, before there is a frame allocator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fixmap {
+    EarlyConsole,
+    LocalApic,
+    IoApic0,
+    IoApic1,
+    IoApic2,
+    IoApic3,
+    Hpet,
+}
+
+impl Fixmap {
+    /// The start of the 2 MiB region reserved for the fixmap
+    pub const START: VirtAddr = VirtAddr::new_truncate(0xffff_ffff_ffe0_0000);
+
+    pub fn page(self) -> Page {
+        Page::new(Self::START + self as u64 * 0x1000, PageSize::_4KiB).unwrap()
+    }
+}
+
+/// Page tables in a `static` that are used to map the fixmap region, so that no frame allocator is needed.
+/// Only the tables that are missing from the L4 table are used.
+#[repr(C, align(4096))]
+pub struct FixmapTables {
+    // This must be the first field, so that it is at the physical address of the struct
+    tables: [UnsafeCell<PageTable>; 3],
+    is_used: AtomicBool,
+}
+
+unsafe impl Sync for FixmapTables {}
+
+impl FixmapTables {
+    pub const fn new() -> Self {
+        Self {
+            tables: [const { UnsafeCell::new(PageTable::new()) }; 3],
+            is_used: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Default for FixmapTables {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debug for FixmapTables {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("FixmapTables")
+            .field("is_used", &self.is_used)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Hands out the frames of [`FixmapTables`]
+struct FixmapTableAllocator {
+    start_addr: PhysAddr,
+    next: u64,
+}
+
+unsafe impl FrameAllocator<Size4KiB> for FixmapTableAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        if self.next == 3 {
+            return None;
+        }
+        let frame = PhysFrame::from_start_address(self.start_addr + self.next * 0x1000).ok()?;
+        self.next += 1;
+        Some(frame)
+    }
+}
+
+#[derive(Debug)]
+pub enum SetFixmapError {
+    /// [`ManagedL4PageTable::init_fixmap`] was not called
+    NotInitialized,
+    Map(MapPageError),
+}
+
+impl ManagedL4PageTable {
+    fn assert_kernel_for_fixmap(&self) {
+        if !matches!(self._type, L4Type::Kernel(_)) {
+            panic!("The fixmap can only be used in a kernel page table");
+        }
+    }
+
+    /// Creates the page tables of the fixmap region, using `tables` for any tables that don't exist yet.
+    /// Panics if this is not a kernel page table, or if `tables` was already used.
+    /// If this fails, it can be called again for this page table.
+    ///
+    /// # Safety
+    /// `tables_phys_addr` must be the physical address of `tables`
+    pub unsafe fn init_fixmap(
+        &mut self,
+        tables: &'static FixmapTables,
+        tables_phys_addr: PhysAddr,
+    ) -> Result<(), MapPageError> {
+        self.assert_kernel_for_fixmap();
+        if tables.is_used.swap(true, Ordering::AcqRel) {
+            panic!("The fixmap tables are already used by a page table");
+        }
+        let result = self.create_fixmap_tables(tables_phys_addr);
+        if result.is_err() {
+            // Every level has its own table, so tables that were already installed are not handed out again when retrying
+            tables.is_used.store(false, Ordering::Release);
+        }
+        result
+    }
+
+    fn create_fixmap_tables(&mut self, tables_phys_addr: PhysAddr) -> Result<(), MapPageError> {
+        let allocator = |next| FixmapTableAllocator {
+            start_addr: tables_phys_addr,
+            next,
+        };
+        let addr = Fixmap::START;
+        let l3 = get_or_create_page_table(
+            self.table_mut().entry_mut(addr.p4_index()),
+            &mut allocator(0),
+        )?;
+        let l2 = get_or_create_page_table(l3.entry_mut(addr.p3_index()), &mut allocator(1))?;
+        get_or_create_page_table(l2.entry_mut(addr.p2_index()), &mut allocator(2))?;
+        Ok(())
+    }
+
+    /// Maps a fixmap slot to a 4 KiB frame, replacing what the slot was mapped to before.
+    /// The page is writable and not executable.
+    ///
+    /// # Safety
+    /// Same as [`ManagedL4PageTable::map_page`]
+    pub unsafe fn set_fixmap(
+        &mut self,
+        slot: Fixmap,
+        frame: Frame,
+        memory_type: PatMemoryType,
+    ) -> Result<(), SetFixmapError> {
+        self.assert_kernel_for_fixmap();
+        unsafe { self.clear_fixmap(slot) };
+        let flags = ConfigurableFlags {
+            writable: true,
+            executable: false,
+            pat_memory_type: memory_type,
+        };
+        unsafe { self.map_page(slot.page(), frame, flags, &mut NoFrameAllocator) }.map_err(|e| {
+            match e {
+                MapPageError::FrameAllocationFailed => SetFixmapError::NotInitialized,
+                e => SetFixmapError::Map(e),
+            }
+        })
+    }
+
+    /// Unmaps a fixmap slot, returning the frame it was mapped to
+    ///
+    /// # Safety
+    /// Nothing can be using the slot's page
+    pub unsafe fn clear_fixmap(&mut self, slot: Fixmap) -> Option<Frame> {
+        self.assert_kernel_for_fixmap();
+        unsafe { self.unmap_page(slot.page()) }.ok()
+    }
+}
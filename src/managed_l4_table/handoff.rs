@@ -0,0 +1,176 @@
+use x86_64::{
+    VirtAddr,
+    instructions::tlb::flush_all,
+    structures::paging::{FrameAllocator, FrameDeallocator, PageTableIndex, Size4KiB},
+};
+
+use crate::*;
+
+use super::{ConfigurableFlags, L4Type, ManagedL4PageTable, MapPageError};
+
+/// The higher half already has mappings, which would be lost by sharing the kernel's higher half
+#[derive(Debug)]
+pub struct HigherHalfNotEmpty;
+
+/// Builds a page table for handing off to the kernel, or for starting application processors.
+/// It identity maps code and data in low memory, such as a trampoline, and also has mappings in the higher half.
+///
+/// Lower half mappings are not user accessible, and no mappings are global, so that the identity maps can be removed with [`ManagedL4PageTable::remove_identity_maps`].
+#[derive(Debug)]
+pub struct HandoffBuilder<'a, A: FrameAllocator<Size4KiB>> {
+    page_table: ManagedL4PageTable,
+    frame_allocator: &'a mut A,
+}
+
+impl PagingConfig {
+    /// This method also zeroes the frame.
+    /// Panics if the frame is not in the direct map.
+    pub fn handoff_builder<A: FrameAllocator<Size4KiB>>(
+        self,
+        frame: Owned4KibFrame,
+        frame_allocator: &mut A,
+    ) -> HandoffBuilder<'_, A> {
+        let mut page_table = self.new_kernel(frame);
+        page_table._type = L4Type::Handoff {
+            shares_kernel_half: false,
+        };
+        HandoffBuilder {
+            page_table,
+            frame_allocator,
+        }
+    }
+}
+
+impl<A: FrameAllocator<Size4KiB>> HandoffBuilder<'_, A> {
+    /// Maps `frame` at the virtual address that is equal to its physical address.
+    /// Panics if that address is not in the lower half.
+    ///
+    /// # Safety
+    /// Same as [`ManagedL4PageTable::map_page`]
+    pub unsafe fn identity_map(
+        mut self,
+        frame: Frame,
+        flags: ConfigurableFlags,
+    ) -> Result<Self, MapPageError> {
+        let addr = VirtAddr::try_new(frame.start_addr().as_u64())
+            .ok()
+            .filter(|addr| u16::from(addr.p4_index()) < 256)
+            .expect("Identity mapped frames must be in the lower half");
+        let page = Page::new(addr, frame.size()).unwrap();
+        unsafe {
+            self.page_table
+                .map_page(page, frame, flags, self.frame_allocator)
+        }?;
+        Ok(self)
+    }
+
+    /// Maps a page in the higher half.
+    /// Panics if the page is not in the higher half, or if the higher half is shared with a kernel page table.
+    ///
+    /// # Safety
+    /// Same as [`ManagedL4PageTable::map_page`]
+    pub unsafe fn map(
+        mut self,
+        page: Page,
+        frame: Frame,
+        flags: ConfigurableFlags,
+    ) -> Result<Self, MapPageError> {
+        assert!(
+            u16::from(page.start_addr().p4_index()) >= 256,
+            "Pages which are not identity mapped must be in the higher half"
+        );
+        unsafe {
+            self.page_table
+                .map_page(page, frame, flags, self.frame_allocator)
+        }?;
+        Ok(self)
+    }
+
+    /// Makes the higher half point to the same page tables as `kernel`, like [`ManagedL4PageTable::new_user`] does.
+    /// After this, only the lower half of this page table can be changed.
+    /// Fails if something was already mapped in the higher half with [`HandoffBuilder::map`].
+    /// Panics if `kernel` is not a kernel page table.
+    pub fn share_kernel_half(
+        mut self,
+        kernel: &mut ManagedL4PageTable,
+    ) -> Result<Self, HigherHalfNotEmpty> {
+        let config = self.page_table.config;
+        for index in (256..512).map(PageTableIndex::new) {
+            if !config.is_reserved_l4_index(index)
+                && !self.page_table.table_mut().entry_mut(index).is_empty()
+            {
+                return Err(HigherHalfNotEmpty);
+            }
+        }
+        kernel.share_kernel_half(&self.page_table);
+        self.page_table._type = L4Type::Handoff {
+            shares_kernel_half: true,
+        };
+        Ok(self)
+    }
+
+    pub fn build(self) -> ManagedL4PageTable {
+        self.page_table
+    }
+}
+
+impl ManagedL4PageTable {
+    /// Unmaps all pages in the lower half of a page table made with [`HandoffBuilder`] with [`ManagedL4PageTable::unmap_page`], and flushes the TLB.
+    /// The identity mapped frames are not freed, but the page tables of the lower half are given to `frame_deallocator`.
+    /// Panics if this page table was not made with [`HandoffBuilder`].
+    ///
+    /// # Safety
+    /// Nothing can be using the identity mapped pages.
+    /// Other CPUs that use this page table must flush their TLBs too.
+    pub unsafe fn remove_identity_maps(
+        &mut self,
+        frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) -> Result<(), UnmapPageError> {
+        if !matches!(self._type, L4Type::Handoff { .. }) {
+            panic!("Only page tables made with a HandoffBuilder have identity maps");
+        }
+        let l4_entry_len = PageSize::_1GiB.byte_len_u64() * 512;
+        for index in (0..256).map(PageTableIndex::new) {
+            if self.table_mut().entry_mut(index).is_empty() {
+                continue;
+            }
+            // The end of the last entry of the lower half is not canonical, so addresses are kept as u64
+            let start = u64::from(u16::from(index)) * l4_entry_len;
+            let end = start + l4_entry_len;
+            let mut addr = start;
+            while addr < end {
+                let virt_addr = VirtAddr::new(addr);
+                let size = match self.leaf_entry_mut(virt_addr) {
+                    Ok(entry) => entry.frame().unwrap().size(),
+                    Err(size) => {
+                        addr = virt_addr.align_down(size.byte_len_u64()).as_u64()
+                            + size.byte_len_u64();
+                        continue;
+                    }
+                };
+                unsafe { self.unmap_page(Page::new(virt_addr, size).unwrap()) }?;
+                addr += size.byte_len_u64();
+            }
+            self.free_empty_tables(
+                VirtAddr::new(start)..VirtAddr::new(end - 1),
+                frame_deallocator,
+            );
+            let entry = self.table_mut().entry_mut(index);
+            let l3_frame = entry.entry.frame(false).unwrap();
+            let Ok(l3) = entry.get_page_table_mut() else {
+                continue;
+            };
+            if !unsafe { l3.page_table.as_ref() }
+                .iter()
+                .all(|entry| entry.is_unused())
+            {
+                continue;
+            }
+            self.table_mut().entry_mut(index).entry.set_unused();
+            self.config.mark_page_table(l3_frame, false);
+            unsafe { frame_deallocator.deallocate_frame(l3_frame) };
+        }
+        flush_all();
+        Ok(())
+    }
+}
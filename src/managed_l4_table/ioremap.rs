@@ -0,0 +1,254 @@
+use core::{fmt::Debug, ops::Range};
+
+use x86_64::{
+    PhysAddr, VirtAddr,
+    registers::model_specific::PatMemoryType,
+    structures::paging::{FrameAllocator, PhysFrame, Size4KiB},
+};
+
+use crate::*;
+
+use super::ManagedL4PageTable;
+
+#[derive(Debug, Clone)]
+struct IoremapEntry {
+    phys_range: Range<PhysAddr>,
+    memory_type: PatMemoryType,
+    virt_range: Range<VirtAddr>,
+    count: usize,
+}
+
+#[derive(Debug)]
+pub enum IoremapError {
+    /// The physical range is empty
+    EmptyRange,
+    /// There is no virtual address range that is big enough left in the window
+    NoVirtualSpace,
+    /// There is no space left to keep track of another mapping
+    Full,
+    /// Part of the physical range is already mapped with a different memory type
+    Conflict(PatMemoryType),
+    Map(MapPageError),
+}
+
+/// Keeps track of MMIO mappings made with [`ManagedL4PageTable::ioremap`] in a window of the higher half, so that mapping the same range again reuses the mapping.
+///
+/// Mappings are kept in a fixed size array, so that this can be put in a `static`.
+pub struct Ioremap<const N: usize> {
+    virtual_allocator: KernelVirtualAllocator<N>,
+    entries: SpinLock<[Option<IoremapEntry>; N]>,
+}
+
+impl<const N: usize> Ioremap<N> {
+    /// `window` must be 4 KiB aligned and in the higher half, and must not be used for anything else
+    pub const fn new(window: Range<VirtAddr>) -> Self {
+        Self {
+            virtual_allocator: KernelVirtualAllocator::new(window),
+            entries: SpinLock::new([const { None }; N]),
+        }
+    }
+}
+
+impl<const N: usize> Debug for Ioremap<N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Ioremap")
+            .field("capacity", &N)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The largest page size that can map `phys_addr` at `virt_addr` without going past `end`
+fn chunk_size(phys_addr: PhysAddr, virt_addr: VirtAddr, end: PhysAddr) -> PageSize {
+    [PageSize::_1GiB, PageSize::_2MiB, PageSize::_4KiB]
+        .into_iter()
+        .find(|size| {
+            *size <= max_page_size()
+                && phys_addr.is_aligned(size.byte_len_u64())
+                && virt_addr.is_aligned(size.byte_len_u64())
+                && end - phys_addr >= size.byte_len_u64()
+        })
+        .unwrap()
+}
+
+/// A MMIO range mapped with [`ManagedL4PageTable::ioremap`].
+/// The range is unmapped when the last [`MmioRegion`] for it is dropped.
+#[derive(Debug)]
+pub struct MmioRegion<const N: usize> {
+    ioremap: &'static Ioremap<N>,
+    config: PagingConfig,
+    l4_frame: PhysFrame,
+    phys_range: Range<PhysAddr>,
+    virt_addr: VirtAddr,
+}
+
+impl<const N: usize> MmioRegion<N> {
+    /// The virtual address of the start of the physical range that was mapped
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.virt_addr
+    }
+
+    pub fn phys_range(&self) -> Range<PhysAddr> {
+        self.phys_range.clone()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.phys_range.end - self.phys_range.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.virt_addr.as_mut_ptr()
+    }
+}
+
+impl<const N: usize> Drop for MmioRegion<N> {
+    fn drop(&mut self) {
+        let mut entries = self.ioremap.entries.lock();
+        let slot = entries
+            .iter_mut()
+            .find(|entry| {
+                entry
+                    .as_ref()
+                    .is_some_and(|entry| entry.virt_range.contains(&self.virt_addr))
+            })
+            .unwrap();
+        let entry = slot.as_mut().unwrap();
+        entry.count -= 1;
+        if entry.count > 0 {
+            return;
+        }
+        let entry = slot.take().unwrap();
+        let mut table = unsafe {
+            ManagedL4PageTable::from_mapping(
+                self.config,
+                Mapping {
+                    l4_frame: self.l4_frame,
+                    page: Page::new(entry.virt_range.start, PageSize::_4KiB).unwrap(),
+                },
+            )
+        };
+        let mut phys_addr = entry.phys_range.start;
+        let mut virt_addr = entry.virt_range.start;
+        while phys_addr < entry.phys_range.end {
+            let size = chunk_size(phys_addr, virt_addr, entry.phys_range.end);
+            // The pages were mapped with the same sizes by ioremap
+            let _ = unsafe { table.unmap_page(Page::new(virt_addr, size).unwrap()) };
+            phys_addr += size.byte_len_u64();
+            virt_addr += size.byte_len_u64();
+        }
+        self.ioremap.virtual_allocator.free(entry.virt_range);
+    }
+}
+
+impl ManagedL4PageTable {
+    /// Maps a physical range, such as a device's BAR, in the window of `ioremap`, with writable and non-executable pages.
+    /// The largest pages that fit are used, and the virtual range is aligned like the physical range so that large pages can be used.
+    ///
+    /// If the same range (expanded to 4 KiB boundaries) is already mapped with the same memory type, the existing mapping is reused.
+    /// Fails if any part of the range is already mapped with a different memory type, since the CPU must not have mappings of the same memory with different types.
+    /// Page tables are not freed when the range is unmapped.
+    ///
+    /// This must be a kernel page table.
+    ///
+    /// # Safety
+    /// Same as [`ManagedL4PageTable::map_page`].
+    /// The returned [`MmioRegion`] unmaps the range in this page table when the last one for it is dropped, so this page table must still exist then and must not be accessed at the same time.
+    pub unsafe fn ioremap<const N: usize>(
+        &mut self,
+        ioremap: &'static Ioremap<N>,
+        phys_range: Range<PhysAddr>,
+        memory_type: PatMemoryType,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<MmioRegion<N>, IoremapError> {
+        if phys_range.is_empty() {
+            return Err(IoremapError::EmptyRange);
+        }
+        let aligned_range = phys_range.start.align_down(PageSize::_4KiB.byte_len_u64())
+            ..phys_range.end.align_up(PageSize::_4KiB.byte_len_u64());
+        let offset = phys_range.start - aligned_range.start;
+        let (config, l4_frame) = (self.config, self.frame.0);
+        let region = |virt_start: VirtAddr| MmioRegion {
+            ioremap,
+            config,
+            l4_frame,
+            phys_range: phys_range.clone(),
+            virt_addr: virt_start + offset,
+        };
+        let mut entries = ioremap.entries.lock();
+        if let Some(entry) = entries.iter().flatten().find(|entry| {
+            entry.memory_type != memory_type
+                && entry.phys_range.start < aligned_range.end
+                && aligned_range.start < entry.phys_range.end
+        }) {
+            return Err(IoremapError::Conflict(entry.memory_type));
+        }
+        if let Some(entry) = entries
+            .iter_mut()
+            .flatten()
+            .find(|entry| entry.phys_range == aligned_range)
+        {
+            entry.count += 1;
+            return Ok(region(entry.virt_range.start));
+        }
+        let slot = entries
+            .iter_mut()
+            .find(|entry| entry.is_none())
+            .ok_or(IoremapError::Full)?;
+
+        let len = aligned_range.end - aligned_range.start;
+        let align = [PageSize::_1GiB, PageSize::_2MiB, PageSize::_4KiB]
+            .into_iter()
+            .find(|size| {
+                *size <= max_page_size()
+                    && aligned_range.start.is_aligned(size.byte_len_u64())
+                    && len >= size.byte_len_u64()
+            })
+            .unwrap_or(PageSize::_4KiB);
+        let virt_range = ioremap
+            .virtual_allocator
+            .allocate(len, align.byte_len_u64())
+            .ok_or(IoremapError::NoVirtualSpace)?;
+        let flags = ConfigurableFlags {
+            writable: true,
+            executable: false,
+            pat_memory_type: memory_type,
+        };
+        let mut phys_addr = aligned_range.start;
+        let mut virt_addr = virt_range.start;
+        while phys_addr < aligned_range.end {
+            let size = chunk_size(phys_addr, virt_addr, aligned_range.end);
+            let result = unsafe {
+                self.map_page(
+                    Page::new(virt_addr, size).unwrap(),
+                    Frame::new(phys_addr, size).unwrap(),
+                    flags,
+                    frame_allocator,
+                )
+            };
+            if let Err(e) = result {
+                let mut unmap_phys_addr = aligned_range.start;
+                let mut unmap_virt_addr = virt_range.start;
+                while unmap_phys_addr < phys_addr {
+                    let size = chunk_size(unmap_phys_addr, unmap_virt_addr, aligned_range.end);
+                    let _ = unsafe { self.unmap_page(Page::new(unmap_virt_addr, size).unwrap()) };
+                    unmap_phys_addr += size.byte_len_u64();
+                    unmap_virt_addr += size.byte_len_u64();
+                }
+                ioremap.virtual_allocator.free(virt_range);
+                return Err(IoremapError::Map(e));
+            }
+            phys_addr += size.byte_len_u64();
+            virt_addr += size.byte_len_u64();
+        }
+        *slot = Some(IoremapEntry {
+            phys_range: aligned_range,
+            memory_type,
+            virt_range: virt_range.clone(),
+            count: 1,
+        });
+        Ok(region(virt_range.start))
+    }
+}
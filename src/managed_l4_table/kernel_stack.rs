@@ -0,0 +1,118 @@
+use core::ops::Range;
+
+use x86_64::{
+    VirtAddr,
+    registers::model_specific::PatMemoryType,
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+};
+
+use crate::*;
+
+use super::ManagedL4PageTable;
+
+/// The number of unmapped pages below every kernel stack
+const GUARD_PAGES: u64 = 1;
+
+/// A kernel stack mapped by [`ManagedL4PageTable::alloc_kernel_stack`], with an unmapped guard page below it.
+/// The stack is unmapped and its frames are freed when this is dropped, with [`FrameDatabase::release`] if a [`FrameDatabase`] is attached.
+/// Dropping only flushes the TLB of the current CPU, so other CPUs that used the stack must flush it before it is dropped, for example with a [`TlbShootdownBatch`] of [`KernelStack::stack`].
+#[derive(Debug)]
+pub struct KernelStack<const N: usize, A: FrameDeallocator<Size4KiB> + 'static> {
+    config: PagingConfig,
+    l4_frame: PhysFrame,
+    virtual_allocator: &'static KernelVirtualAllocator<N>,
+    frame_deallocator: &'static SpinLock<A>,
+    /// Includes the guard page
+    range: Range<VirtAddr>,
+}
+
+impl<const N: usize, A: FrameDeallocator<Size4KiB>> KernelStack<N, A> {
+    /// The initial stack pointer, which is the end of the stack
+    pub fn top(&self) -> VirtAddr {
+        self.range.end
+    }
+
+    /// The mapped part of the stack
+    pub fn stack(&self) -> Range<VirtAddr> {
+        self.guard().end..self.range.end
+    }
+
+    /// The unmapped pages below the stack
+    pub fn guard(&self) -> Range<VirtAddr> {
+        self.range.start..self.range.start + GUARD_PAGES * PageSize::_4KiB.byte_len_u64()
+    }
+
+    /// Returns `true` if `addr` (such as the CR2 value of a page fault) is in the guard page, which means that the stack overflowed
+    pub fn is_guard_page(&self, addr: VirtAddr) -> bool {
+        self.guard().contains(&addr)
+    }
+}
+
+impl<const N: usize, A: FrameDeallocator<Size4KiB>> Drop for KernelStack<N, A> {
+    fn drop(&mut self) {
+        let mut table = unsafe {
+            ManagedL4PageTable::from_mapping(
+                self.config,
+                Mapping {
+                    l4_frame: self.l4_frame,
+                    page: Page::new(self.stack().start, PageSize::_4KiB).unwrap(),
+                },
+            )
+        };
+        // Every page of the stack was mapped when it was allocated, so unmapping cannot fail.
+        // The error is ignored since a drop cannot return it.
+        let _ = unsafe { table.vfree_pages(self.stack(), &mut *self.frame_deallocator.lock()) };
+        self.virtual_allocator.free(self.range.clone());
+    }
+}
+
+impl ManagedL4PageTable {
+    /// Allocates a virtual range from `virtual_allocator` for a stack of `pages` 4 KiB pages plus a guard page below it,
+    /// and maps the stack to new writable and non-executable frames.
+    /// The guard page stays unmapped, so a stack overflow causes a page fault.
+    /// Fails with [`VmallocError::InvalidSize`] if `pages` is 0 or the size overflows.
+    ///
+    /// This must be a kernel page table.
+    ///
+    /// # Safety
+    /// The returned [`KernelStack`] unmaps the stack in this page table when it is dropped, so this page table must still exist then and must not be accessed at the same time.
+    /// It must not be dropped while the stack is being used, and `frame_allocator` must not be locked on the CPU that drops it.
+    /// Other CPUs must not have the stack in their TLBs when it is dropped.
+    pub unsafe fn alloc_kernel_stack<const N: usize, A>(
+        &mut self,
+        virtual_allocator: &'static KernelVirtualAllocator<N>,
+        pages: u64,
+        frame_allocator: &'static SpinLock<A>,
+    ) -> Result<KernelStack<N, A>, VmallocError>
+    where
+        A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+    {
+        if pages == 0 {
+            return Err(VmallocError::InvalidSize);
+        }
+        let len = pages
+            .checked_add(GUARD_PAGES)
+            .and_then(|pages| pages.checked_mul(PageSize::_4KiB.byte_len_u64()))
+            .ok_or(VmallocError::InvalidSize)?;
+        let range = virtual_allocator
+            .allocate(len, PageSize::_4KiB.byte_len_u64())
+            .ok_or(VmallocError::NoVirtualSpace)?;
+        let flags = ConfigurableFlags {
+            writable: true,
+            executable: false,
+            pat_memory_type: PatMemoryType::WriteBack,
+        };
+        let stack = range.start + GUARD_PAGES * PageSize::_4KiB.byte_len_u64()..range.end;
+        if let Err(e) = unsafe { self.map_new_frames(stack, flags, &mut *frame_allocator.lock()) } {
+            virtual_allocator.free(range);
+            return Err(e);
+        }
+        Ok(KernelStack {
+            config: self.config,
+            l4_frame: self.frame.0,
+            virtual_allocator,
+            frame_deallocator: frame_allocator,
+            range,
+        })
+    }
+}
@@ -1,23 +1,36 @@
 use core::{ops::RangeInclusive, ptr::NonNull};
 
 use x86_64::{
+    VirtAddr,
     registers::control::{Cr3, Cr3Flags},
-    structures::paging::{PageTable, PageTableIndex},
+    structures::paging::{
+        PageTable, PageTableIndex, frame::PhysFrameRange, page_table::PageTableEntry,
+    },
 };
 
 use crate::*;
 
-use super::page_table_with_level::{PageTableLevel, PageTableWithLevelMut};
+use super::{
+    GetTableError, PageTableEntryWithLevelMut,
+    page_table_with_level::{PageTableLevel, PageTableWithLevelMut},
+};
 
 #[derive(Debug)]
 pub struct KernelL4Data {
-    is_referenced: bool,
+    pub(super) is_referenced: bool,
+    /// Page tables from a [`StaticTablePool`], which must never be freed
+    pub(super) static_tables: Option<PhysFrameRange>,
 }
 
 #[derive(Debug)]
 pub(super) enum L4Type {
     User,
     Kernel(KernelL4Data),
+    /// Has identity maps in the lower half which only the kernel can access, see [`HandoffBuilder`]
+    Handoff {
+        /// The higher half belongs to a kernel page table, so only the lower half can be changed
+        shares_kernel_half: bool,
+    },
 }
 
 impl L4Type {
@@ -25,13 +38,19 @@ impl L4Type {
         match self {
             Self::User => PageTableIndex::new(0)..=PageTableIndex::new(255),
             Self::Kernel(_) => PageTableIndex::new(256)..=PageTableIndex::new(511),
+            Self::Handoff {
+                shares_kernel_half: true,
+            } => PageTableIndex::new(0)..=PageTableIndex::new(255),
+            Self::Handoff {
+                shares_kernel_half: false,
+            } => PageTableIndex::new(0)..=PageTableIndex::new(511),
         }
     }
 
     pub fn can_create_new_l4_entries(&self) -> bool {
         match self {
-            Self::User => true,
-            Self::Kernel(KernelL4Data { is_referenced }) => !is_referenced,
+            Self::User | Self::Handoff { .. } => true,
+            Self::Kernel(KernelL4Data { is_referenced, .. }) => !is_referenced,
         }
     }
 }
@@ -40,23 +59,15 @@ impl L4Type {
 pub struct ManagedL4PageTable {
     pub(super) frame: Owned4KibFrame,
     pub(super) _type: L4Type,
-    pub(super) config: PagingConfig,
+    pub(crate) config: PagingConfig,
 }
 
 /// # Safety
 /// Frame will be zeroed
 unsafe fn init_page_table(frame: &mut Owned4KibFrame, config: &PagingConfig) {
-    let ptr = NonNull::new(
-        frame
-            .start_address()
-            .to_virt(config)
-            .as_mut_ptr::<PageTable>(),
-    )
-    .unwrap();
-    // We use `write_bytes` so that we don't put the 4 KiB page table on the stack, which causes stack overflows.
-    unsafe {
-        ptr.write_bytes(0, 1);
-    }
+    unsafe { config.init_l4_table(frame.0) }
+        .expect("page table frames must be in the direct map, unless using kmap or recursive mode");
+    config.mark_page_table(frame.0, true);
 }
 
 impl PagingConfig {
@@ -64,12 +75,15 @@ impl PagingConfig {
     /// You will only be allowed to use the higher half of the virtual address space.
     ///
     /// This method also zeroes the frame.
+    /// In kmap mode, the [`KmapWindow`] is installed in the new table.
+    /// Panics if the frame is not in the direct map.
     pub fn new_kernel(self, mut frame: Owned4KibFrame) -> ManagedL4PageTable {
         unsafe { init_page_table(&mut frame, &self) };
         ManagedL4PageTable {
             frame,
             _type: L4Type::Kernel(KernelL4Data {
                 is_referenced: false,
+                static_tables: None,
             }),
             config: self,
         }
@@ -81,46 +95,141 @@ impl ManagedL4PageTable {
     /// You will only be able to use the lower half of the virtual address space.
     ///
     /// This method also zeroes the frame.
+    /// Panics if the frame is not in the direct map.
     pub fn new_user(&mut self, mut frame: Owned4KibFrame) -> Self {
+        unsafe { init_page_table(&mut frame, &self.config) };
+        let lower_half = Self {
+            frame,
+            _type: L4Type::User,
+            config: self.config,
+        };
+        self.share_kernel_half(&lower_half);
+        lower_half
+    }
+
+    /// Makes the higher half of `other` point to the same page tables as this kernel page table
+    pub(super) fn share_kernel_half(&mut self, other: &Self) {
         match &mut self._type {
-            L4Type::User => {
+            L4Type::User | L4Type::Handoff { .. } => {
                 panic!("self must be a kernel's l4 frame to copy from it")
             }
-            L4Type::Kernel(KernelL4Data { is_referenced }) => {
+            L4Type::Kernel(KernelL4Data { is_referenced, .. }) => {
                 *is_referenced = true;
             }
         };
-        unsafe { init_page_table(&mut frame, &self.config) };
-        let mut lower_half = Self {
-            frame,
-            _type: L4Type::User,
-            config: self.config,
-        };
+        // In recursive mode only one L4 table can be accessed at a time, so the entries are copied through the stack
+        let mut kernel_entries = [const { PageTableEntry::new() }; 256];
         let range_to_copy = self._type.l4_managed_entry_range();
-        let kernel_page_table = unsafe { self.page_table().as_mut() };
-        let user_page_table = unsafe { lower_half.page_table().as_mut() };
-        for index in range_to_copy {
-            user_page_table[index].clone_from(&kernel_page_table[index]);
+        let mut kernel_table_ptr = self.mapped_page_table();
+        let kernel_page_table = unsafe { kernel_table_ptr.ptr.as_mut() };
+        for (entry, index) in kernel_entries.iter_mut().zip(range_to_copy.clone()) {
+            entry.clone_from(&kernel_page_table[index]);
+        }
+        drop(kernel_table_ptr);
+        let reserved_indexes = self.config.reserved_l4_indexes();
+        let mut other_table_ptr = other.mapped_page_table();
+        let other_page_table = unsafe { other_table_ptr.ptr.as_mut() };
+        for (entry, index) in kernel_entries.iter().zip(range_to_copy) {
+            // The recursive entry was already set to point to the other page table
+            if reserved_indexes
+                .is_some_and(|indexes| index == indexes.recursive || index == indexes.window)
+            {
+                continue;
+            }
+            other_page_table[index].clone_from(entry);
         }
-        lower_half
     }
 
     /// If you choose to manually modify page table entries, be careful, because it could create valid page tables that will cause problems because this crate doesn't expect handle.
+    ///
+    /// In recursive mode, the pointer is only valid until another L4 table is accessed.
+    /// Panics in kmap mode, since the table is only mapped while it is being accessed.
     pub fn page_table(&mut self) -> NonNull<PageTable> {
-        NonNull::new(
-            self.frame
-                .start_address()
-                .to_virt(&self.config)
-                .as_mut_ptr::<PageTable>(),
-        )
-        .unwrap()
+        if self.config.kmap_window().is_some() {
+            panic!("The L4 table does not have a permanent address in kmap mode");
+        }
+        self.mapped_page_table().ptr
+    }
+
+    pub(super) fn mapped_page_table(&self) -> TablePtr {
+        self.config
+            .l4_table_ptr(self.frame.0)
+            .expect("the frame was checked to be accessible when creating the page table")
     }
 
     pub(super) fn table_mut(&mut self) -> PageTableWithLevelMut {
+        let table_ptr = self.mapped_page_table();
         PageTableWithLevelMut {
-            page_table: self.page_table(),
+            page_table: table_ptr.ptr,
             level: PageTableLevel::L4,
             l4: self,
+            kmap_guard: table_ptr.kmap_guard,
+        }
+    }
+
+    /// Walks the page tables to the entry that maps `page`, without creating any page tables
+    pub(super) fn page_entry_mut(
+        &mut self,
+        page: Page,
+    ) -> Result<PageTableEntryWithLevelMut<'_>, GetTableError> {
+        let l3 = self
+            .table_mut()
+            .entry_mut(page.start_addr().p4_index())
+            .get_page_table_mut()?;
+        let l3_entry = l3.entry_mut(page.start_addr().p3_index());
+        if let PageSize::_1GiB = page.size() {
+            return Ok(l3_entry);
+        }
+        let l2_entry = l3_entry
+            .get_page_table_mut()?
+            .entry_mut(page.start_addr().p2_index());
+        if let PageSize::_2MiB = page.size() {
+            return Ok(l2_entry);
+        }
+        Ok(l2_entry
+            .get_page_table_mut()?
+            .entry_mut(page.start_addr().p1_index()))
+    }
+
+    /// Returns `true` if the L4 entry of `addr` is managed by this page table and not reserved for accessing page tables, so that it can be walked without panicking
+    pub(super) fn manages_addr(&self, addr: VirtAddr) -> bool {
+        let index = addr.p4_index();
+        self._type.l4_managed_entry_range().contains(&index)
+            && !self.config.is_reserved_l4_index(index)
+    }
+
+    /// Walks the page tables to the entry that maps `addr`, whatever its page size is.
+    /// If `addr` is not mapped, the size of the unmapped region around `addr` (at most 1 GiB) is returned as the error.
+    pub(super) fn leaf_entry_mut(
+        &mut self,
+        addr: VirtAddr,
+    ) -> Result<PageTableEntryWithLevelMut<'_>, PageSize> {
+        let l4_entry = self.table_mut().entry_mut(addr.p4_index());
+        if l4_entry.is_empty() {
+            return Err(PageSize::_1GiB);
+        }
+        let l3_entry = l4_entry
+            .get_page_table_mut()
+            .map_err(|_| PageSize::_1GiB)?
+            .entry_mut(addr.p3_index());
+        if l3_entry.frame().is_some() {
+            return Ok(l3_entry);
+        }
+        let l2_entry = l3_entry
+            .get_page_table_mut()
+            .map_err(|_| PageSize::_1GiB)?
+            .entry_mut(addr.p2_index());
+        if l2_entry.frame().is_some() {
+            return Ok(l2_entry);
+        }
+        let l1_entry = l2_entry
+            .get_page_table_mut()
+            .map_err(|_| PageSize::_2MiB)?
+            .entry_mut(addr.p1_index());
+        if l1_entry.frame().is_some() {
+            Ok(l1_entry)
+        } else {
+            Err(PageSize::_4KiB)
         }
     }
 
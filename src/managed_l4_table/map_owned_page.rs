@@ -0,0 +1,65 @@
+use x86_64::structures::paging::{FrameAllocator, PageTableFlags, Size4KiB};
+
+use crate::*;
+
+use super::{GetTableError, ManagedL4PageTable};
+
+/// Marks entries that map an [`OwnedFrame`]
+pub(super) const OWNED_FRAME: PageTableFlags = PageTableFlags::BIT_9;
+
+/// The frame is given back when mapping fails, so that it is not leaked
+#[derive(Debug)]
+pub struct MapOwnedPageError {
+    pub error: MapPageError,
+    pub frame: OwnedFrame,
+}
+
+#[derive(Debug)]
+pub enum UnmapOwnedPageError {
+    GetTable(GetTableError),
+    /// The page is not mapped to an [`OwnedFrame`], so it must be unmapped with [`ManagedL4PageTable::unmap_page`]
+    NotOwned,
+    Unmap(UnmapPageError),
+}
+
+impl ManagedL4PageTable {
+    /// Like [`ManagedL4PageTable::map_page`], but the page table takes ownership of the frame.
+    /// The entry is marked as owned, so it can only be unmapped with [`ManagedL4PageTable::unmap_owned_page`], and it cannot be split.
+    ///
+    /// # Safety
+    /// Same as [`ManagedL4PageTable::map_page`]
+    pub unsafe fn map_owned_page(
+        &mut self,
+        page: Page,
+        frame: OwnedFrame,
+        flags: ConfigurableFlags,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<(), MapOwnedPageError> {
+        if let Err(error) = unsafe { self.map_page(page, *frame, flags, frame_allocator) } {
+            return Err(MapOwnedPageError { error, frame });
+        }
+        let mut entry = self.page_entry_mut(page).expect("the page was just mapped");
+        entry.set_software_flags(entry.software_flags() | OWNED_FRAME);
+        Ok(())
+    }
+
+    /// Unmaps a page that was mapped with [`ManagedL4PageTable::map_owned_page`] and gives back ownership of the frame.
+    /// Also does `invlpg` after successfully un-mapping.
+    ///
+    /// # Safety
+    /// Same as [`ManagedL4PageTable::unmap_page`]. Other CPUs could still have the page in their TLBs, so the frame must not be reused until they flush it.
+    pub unsafe fn unmap_owned_page(
+        &mut self,
+        page: Page,
+    ) -> Result<OwnedFrame, UnmapOwnedPageError> {
+        let mut entry = self
+            .page_entry_mut(page)
+            .map_err(UnmapOwnedPageError::GetTable)?;
+        if entry.frame().is_none() || !entry.software_flags().contains(OWNED_FRAME) {
+            return Err(UnmapOwnedPageError::NotOwned);
+        }
+        entry.set_software_flags(entry.software_flags() - OWNED_FRAME);
+        let frame = unsafe { self.unmap_page(page) }.map_err(UnmapOwnedPageError::Unmap)?;
+        Ok(OwnedFrame(frame))
+    }
+}
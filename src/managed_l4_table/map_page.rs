@@ -3,8 +3,8 @@ use x86_64::structures::paging::{FrameAllocator, Size4KiB};
 use crate::*;
 
 use super::{
-    GetTableError, ManagedL4PageTable, PageTableEntryWithLevelMut, SetFrameError, SetTableError,
-    page_table_with_level::PageTableWithLevelMut,
+    GetTableError, ManagedL4PageTable, MemTypeError, PageTableEntryWithLevelMut, SetFrameError,
+    SetTableError, page_table_with_level::PageTableWithLevelMut,
 };
 
 #[derive(Debug)]
@@ -13,9 +13,11 @@ pub enum MapPageError {
     SetTable(SetTableError),
     GetTable(GetTableError),
     SetFrame(SetFrameError),
+    MemType(MemTypeError),
+    ReverseMap(RecordMappingError),
 }
 
-fn get_or_create_page_table<'a>(
+pub(super) fn get_or_create_page_table<'a>(
     page_table_entry: PageTableEntryWithLevelMut<'a>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<PageTableWithLevelMut<'a>, MapPageError> {
@@ -24,9 +26,12 @@ fn get_or_create_page_table<'a>(
             let frame = frame_allocator
                 .allocate_frame()
                 .ok_or(MapPageError::FrameAllocationFailed)?;
-            page_table_entry
-                .set_page_table(frame)
-                .map_err(MapPageError::SetTable)?
+            let config = page_table_entry.l4.config;
+            config.mark_page_table(frame, true);
+            page_table_entry.set_page_table(frame).map_err(|e| {
+                config.mark_page_table(frame, false);
+                MapPageError::SetTable(e)
+            })?
         } else {
             page_table_entry
                 .get_page_table_mut()
@@ -41,6 +46,11 @@ impl ManagedL4PageTable {
     ///
     /// PRESENT and HUGE_PAGE flags are automatically added as needed.
     ///
+    /// If a [`MemTypeDatabase`] is attached to the [`PagingConfig`], the frame's memory type is reserved first.
+    /// Write-back mappings are only checked against the reservations, so they do not use space in the database.
+    /// If a [`FrameDatabase`] is attached, the frame's map count is increased.
+    /// If a [`ReverseMap`] is attached, the mapping is recorded in it.
+    ///
     /// # Safety
     /// Don't mess up page tables, don't give user mode access to things it shouldn't access, don't accidentally create multiple &mut T to the same data.
     pub unsafe fn map_page(
@@ -49,6 +59,62 @@ impl ManagedL4PageTable {
         frame: Frame,
         flags: ConfigurableFlags,
         frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<(), MapPageError> {
+        self.track_mem_type(page, frame, flags.pat_memory_type, frame_allocator)
+            .map_err(MapPageError::MemType)?;
+        let result = unsafe { self.map_page_untracked(page, frame, flags, frame_allocator) };
+        if result.is_err() {
+            self.untrack_mem_type(page, frame, flags.pat_memory_type);
+        }
+        result
+    }
+
+    /// Same as [`ManagedL4PageTable::map_page`], but the memory type is not reserved.
+    /// The frame's memory type must already be reserved, such as with [`ManagedL4PageTable::track_mem_type_range`], and the page must be unmapped with [`ManagedL4PageTable::unmap_page_untracked`].
+    pub(super) unsafe fn map_page_untracked(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        flags: ConfigurableFlags,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<(), MapPageError> {
+        let mapping = Mapping {
+            l4_frame: self.frame.0,
+            page,
+        };
+        if let Some(reverse_map) = self.config.reverse_map {
+            reverse_map
+                .record(frame, mapping)
+                .map_err(MapPageError::ReverseMap)?;
+        }
+        let result = self.set_page_frame(page, frame, flags, frame_allocator);
+        match result {
+            Ok(()) => {
+                if let Some(meta) = self.frame_meta(frame) {
+                    meta.add_mapping();
+                }
+            }
+            Err(_) => {
+                if let Some(reverse_map) = self.config.reverse_map {
+                    reverse_map.remove(frame, mapping);
+                }
+            }
+        }
+        result
+    }
+
+    pub(super) fn frame_meta(&self, frame: Frame) -> Option<&'static FrameMeta> {
+        self.config.frame_database?.frame(frame)
+    }
+
+    /// Only writes the page table entry, creating page tables as needed.
+    /// Nothing is recorded in the [`MemTypeDatabase`], [`FrameDatabase`] or [`ReverseMap`].
+    pub(super) fn set_page_frame(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        flags: ConfigurableFlags,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
     ) -> Result<(), MapPageError> {
         let l4 = self.table_mut();
         let l3 =
@@ -0,0 +1,132 @@
+use core::ops::Range;
+
+use x86_64::{
+    PhysAddr, VirtAddr,
+    registers::model_specific::PatMemoryType,
+    structures::paging::{FrameAllocator, Size4KiB},
+};
+
+use crate::*;
+
+use super::{ConfigurableFlags, ManagedL4PageTable, MapPageError, MemTypeError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhysRegionKind {
+    /// Usable memory, which is mapped as write-back
+    Ram,
+    /// ACPI tables and NVS memory, which are mapped as write-back
+    Acpi,
+    /// Device memory, which is mapped as uncacheable
+    Mmio,
+    /// Memory that the firmware reserved, which is mapped as uncacheable
+    Reserved,
+}
+
+impl PhysRegionKind {
+    pub fn memory_type(self) -> PatMemoryType {
+        match self {
+            Self::Ram | Self::Acpi => PatMemoryType::WriteBack,
+            Self::Mmio | Self::Reserved => PatMemoryType::StrongUncacheable,
+        }
+    }
+}
+
+/// An entry in a memory map
+#[derive(Debug, Clone)]
+pub struct PhysRegion {
+    pub range: Range<PhysAddr>,
+    pub kind: PhysRegionKind,
+}
+
+#[derive(Debug)]
+pub enum MapPhysicalMemoryError {
+    Map(MapPageError),
+    MemType(MemTypeError),
+    /// `mapped_ranges` is too small to hold the mapped ranges, even after merging ranges that touch
+    TooManyRanges,
+}
+
+/// Adds `range` to the first `len` ranges, merging it with the ranges it touches
+fn add_mapped_range(
+    ranges: &mut [Range<PhysAddr>],
+    len: &mut usize,
+    range: Range<PhysAddr>,
+) -> Result<(), MapPhysicalMemoryError> {
+    let mut merged = range;
+    let mut index = 0;
+    while index < *len {
+        if ranges[index].start <= merged.end && merged.start <= ranges[index].end {
+            merged = ranges[index].start.min(merged.start)..ranges[index].end.max(merged.end);
+            *len -= 1;
+            ranges.swap(index, *len);
+        } else {
+            index += 1;
+        }
+    }
+    *ranges
+        .get_mut(*len)
+        .ok_or(MapPhysicalMemoryError::TooManyRanges)? = merged;
+    *len += 1;
+    Ok(())
+}
+
+impl ManagedL4PageTable {
+    /// Maps every region at `physical address + offset`, using the largest pages that fit, to create a direct map.
+    /// All pages are writable and not executable.
+    /// Regions are expanded to 4 KiB boundaries, and must not overlap.
+    ///
+    /// The mapped ranges are stored in `mapped_ranges`, with ranges that touch merged, and the returned [`VirtualOffset`] only treats them as mapped.
+    ///
+    /// The pages are not recorded in the [`FrameDatabase`] or [`ReverseMap`], since they map memory by its address and not by what it is used for.
+    /// If a [`MemTypeDatabase`] is attached, every region that is not write-back is reserved once with its memory type.
+    ///
+    /// # Safety
+    /// Same as [`ManagedL4PageTable::map_page`]
+    pub unsafe fn map_physical_memory(
+        &mut self,
+        offset: u64,
+        regions: impl IntoIterator<Item = PhysRegion>,
+        mapped_ranges: &'static mut [Range<PhysAddr>],
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<VirtualOffset, MapPhysicalMemoryError> {
+        let max_page_size = max_page_size();
+        let mut mapped_len = 0;
+        for region in regions {
+            let flags = ConfigurableFlags {
+                writable: true,
+                executable: false,
+                pat_memory_type: region.kind.memory_type(),
+            };
+            let start = region
+                .range
+                .start
+                .align_down(PageSize::_4KiB.byte_len_u64());
+            let end = region.range.end.align_up(PageSize::_4KiB.byte_len_u64());
+            if start >= end {
+                continue;
+            }
+            add_mapped_range(mapped_ranges, &mut mapped_len, start..end)?;
+            let mut addr = start;
+            while addr < end {
+                let virt_addr = VirtAddr::new(addr.as_u64() + offset);
+                let size = [PageSize::_1GiB, PageSize::_2MiB, PageSize::_4KiB]
+                    .into_iter()
+                    .find(|size| {
+                        *size <= max_page_size
+                            && addr.is_aligned(size.byte_len_u64())
+                            && virt_addr.is_aligned(size.byte_len_u64())
+                            && end - addr >= size.byte_len_u64()
+                    })
+                    .unwrap();
+                let page = Page::new(virt_addr, size).unwrap();
+                let frame = Frame::new(addr, size).unwrap();
+                self.set_page_frame(page, frame, flags, frame_allocator)
+                    .map_err(MapPhysicalMemoryError::Map)?;
+                addr += size.byte_len_u64();
+            }
+            self.track_mem_type_range(start..end, flags.pat_memory_type, frame_allocator)
+                .map_err(MapPhysicalMemoryError::MemType)?;
+        }
+        Ok(unsafe { VirtualOffset::new_sparse(offset, &mapped_ranges[..mapped_len]) })
+    }
+}
@@ -0,0 +1,309 @@
+use core::ops::Range;
+
+use x86_64::{
+    PhysAddr, VirtAddr,
+    registers::model_specific::PatMemoryType,
+    structures::paging::{FrameAllocator, PageTableFlags, PhysFrame, Size4KiB},
+};
+
+use crate::*;
+
+use super::{ManagedL4PageTable, SetFlagsError, SplitPageError, retype_entry};
+
+/// Marks pages of the direct map whose memory type was changed because of [`MemTypeConflictPolicy::RetypeDirectMap`]
+const RETYPED_DIRECT_MAP: PageTableFlags = PageTableFlags::BIT_10;
+
+#[derive(Debug)]
+pub enum MemTypeError {
+    Reserve(ReserveMemTypeError),
+    /// The frame's alias in the direct map has a different memory type, and the policy is [`MemTypeConflictPolicy::Reject`]
+    DirectMapConflict(PatMemoryType),
+    SplitDirectMap(SplitPageError),
+    RetypeDirectMap(SetFlagsError),
+}
+
+/// Used when page tables must not be allocated, such as when the direct map can only be retyped without splitting pages
+pub(super) struct NoFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for NoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        None
+    }
+}
+
+fn frame_range(frame: Frame) -> Range<PhysAddr> {
+    frame.start_addr()..frame.start_addr() + frame.size().byte_len_u64()
+}
+
+impl ManagedL4PageTable {
+    /// The range's alias in the direct map, whatever type of page table this is
+    fn direct_map_alias(&self, range: &Range<PhysAddr>) -> Option<Range<VirtAddr>> {
+        let start = range.start.to_virt(&self.config)?;
+        let end = start + (range.end - range.start);
+        let is_higher_half = |addr: VirtAddr| u16::from(addr.p4_index()) >= 256;
+        let is_reserved = |addr: VirtAddr| self.config.is_reserved_l4_index(addr.p4_index());
+        (is_higher_half(start) == is_higher_half(end - 1u64)
+            && !is_reserved(start)
+            && !is_reserved(end - 1u64))
+        .then_some(start..end)
+    }
+
+    /// Calls `f` with a page table that can change `alias`.
+    /// The higher half of user and handoff page tables points to the same page tables as the kernel page table,
+    /// so if `alias` is not managed by this page table, the same L4 table is accessed as a kernel page table.
+    fn with_direct_map<R>(
+        &mut self,
+        alias: &Range<VirtAddr>,
+        f: impl FnOnce(&mut ManagedL4PageTable) -> R,
+    ) -> R {
+        if self
+            ._type
+            .l4_managed_entry_range()
+            .contains(&alias.start.p4_index())
+        {
+            return f(self);
+        }
+        // This page table is borrowed mutably, so nothing else accesses the L4 table
+        let mut table = unsafe {
+            ManagedL4PageTable::from_mapping(
+                self.config,
+                Mapping {
+                    l4_frame: self.frame.0,
+                    page: Page::new(alias.start, PageSize::_4KiB).unwrap(),
+                },
+            )
+        };
+        f(&mut table)
+    }
+
+    /// Reserves the memory type of a frame that is going to be mapped at `page`, and makes sure that the direct map does not conflict with it.
+    /// Does nothing if there is no [`MemTypeDatabase`] or if `page` is the frame's alias in the direct map.
+    pub(super) fn track_mem_type(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        memory_type: PatMemoryType,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<(), MemTypeError> {
+        self.retrack_mem_type(
+            page,
+            frame,
+            PatMemoryType::WriteBack,
+            memory_type,
+            1,
+            frame_allocator,
+        )
+    }
+
+    /// Same as [`ManagedL4PageTable::track_mem_type`], but cannot split pages in the direct map
+    pub(super) fn track_mem_type_without_allocating(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        memory_type: PatMemoryType,
+    ) -> Result<(), MemTypeError> {
+        self.track_mem_type(page, frame, memory_type, &mut NoFrameAllocator)
+    }
+
+    /// Moves the reservations of `count` pages mapping `frame`, including `page`, from one memory type to another with [`MemTypeDatabase::retype`],
+    /// and changes the frame's alias in the direct map to match.
+    /// Does nothing if there is no [`MemTypeDatabase`] or if `page` is the frame's alias in the direct map.
+    pub(super) fn retrack_mem_type(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        from: PatMemoryType,
+        to: PatMemoryType,
+        count: usize,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<(), MemTypeError> {
+        let range = frame_range(frame);
+        if self
+            .direct_map_alias(&range)
+            .is_some_and(|alias| alias.start == page.start_addr())
+        {
+            return Ok(());
+        }
+        self.retrack_mem_type_range(range, from, to, count, frame_allocator)
+    }
+
+    /// Reserves the memory type of a physical range with a single reservation, and makes sure that the range's alias in the direct map does not conflict with it.
+    /// This is for ranges that are mapped with [`ManagedL4PageTable::map_page_untracked`], so that every page does not use its own space in the [`MemTypeDatabase`].
+    /// Does nothing if there is no [`MemTypeDatabase`].
+    pub(super) fn track_mem_type_range(
+        &mut self,
+        range: Range<PhysAddr>,
+        memory_type: PatMemoryType,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<(), MemTypeError> {
+        self.retrack_mem_type_range(
+            range,
+            PatMemoryType::WriteBack,
+            memory_type,
+            1,
+            frame_allocator,
+        )
+    }
+
+    fn retrack_mem_type_range(
+        &mut self,
+        range: Range<PhysAddr>,
+        from: PatMemoryType,
+        to: PatMemoryType,
+        count: usize,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<(), MemTypeError> {
+        let Some(mem_types) = self.config.mem_types else {
+            return Ok(());
+        };
+        mem_types
+            .database
+            .retype(range.clone(), from, to, count)
+            .map_err(MemTypeError::Reserve)?;
+        let Some(alias) = self.direct_map_alias(&range) else {
+            return Ok(());
+        };
+        if to == PatMemoryType::WriteBack {
+            if mem_types.database.lookup(range).is_none() {
+                self.with_direct_map(&alias.clone(), |table| table.restore_direct_map(alias));
+            }
+            return Ok(());
+        }
+        let result = self.with_direct_map(&alias.clone(), |table| match mem_types.policy {
+            MemTypeConflictPolicy::Reject => table.check_direct_map(alias, to),
+            MemTypeConflictPolicy::RetypeDirectMap => {
+                table.retype_direct_map(alias, to, frame_allocator)
+            }
+        });
+        if let Err(e) = result {
+            // Moving the reservations back cannot conflict, since they were just moved from there
+            let _ = mem_types.database.retype(range, to, from, count);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Frees the reservation made by [`ManagedL4PageTable::track_mem_type`].
+    /// If nothing else reserves the frame anymore, the direct map is changed back to write-back.
+    pub(super) fn untrack_mem_type(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        memory_type: PatMemoryType,
+    ) {
+        let range = frame_range(frame);
+        if self
+            .direct_map_alias(&range)
+            .is_some_and(|alias| alias.start == page.start_addr())
+        {
+            return;
+        }
+        self.untrack_mem_type_range(range, memory_type);
+    }
+
+    /// Frees the reservation made by [`ManagedL4PageTable::track_mem_type_range`].
+    /// If nothing else reserves the range anymore, the direct map is changed back to write-back.
+    pub(super) fn untrack_mem_type_range(
+        &mut self,
+        range: Range<PhysAddr>,
+        memory_type: PatMemoryType,
+    ) {
+        let Some(mem_types) = self.config.mem_types else {
+            return;
+        };
+        if memory_type == PatMemoryType::WriteBack {
+            return;
+        }
+        mem_types.database.free(range.clone(), memory_type);
+        if let Some(alias) = self.direct_map_alias(&range)
+            && mem_types.database.lookup(range).is_none()
+        {
+            self.with_direct_map(&alias.clone(), |table| table.restore_direct_map(alias));
+        }
+    }
+
+    fn check_direct_map(
+        &mut self,
+        alias: Range<VirtAddr>,
+        memory_type: PatMemoryType,
+    ) -> Result<(), MemTypeError> {
+        let mut addr = alias.start;
+        while addr < alias.end {
+            match self.leaf_entry_mut(addr) {
+                Ok(entry) => {
+                    let flags = entry.configurable_flags().unwrap();
+                    if flags.pat_memory_type != memory_type {
+                        return Err(MemTypeError::DirectMapConflict(flags.pat_memory_type));
+                    }
+                    let size = entry.frame().unwrap().size().byte_len_u64();
+                    addr = addr.align_down(size) + size;
+                }
+                Err(size) => addr = addr.align_down(size.byte_len_u64()) + size.byte_len_u64(),
+            }
+        }
+        Ok(())
+    }
+
+    /// Splits the pages that also map memory outside of `alias` first, so that no page is retyped if splitting fails
+    fn retype_direct_map(
+        &mut self,
+        alias: Range<VirtAddr>,
+        memory_type: PatMemoryType,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<(), MemTypeError> {
+        // Pages are aligned to their size, so only the pages at both ends can also map memory outside of the range
+        for addr in [alias.start, alias.end - 1u64] {
+            while let Ok(entry) = self.leaf_entry_mut(addr) {
+                let size = entry.frame().unwrap().size();
+                let page = Page::new(addr.align_down(size.byte_len_u64()), size).unwrap();
+                if page.start_addr() >= alias.start
+                    && page.start_addr() + size.byte_len_u64() <= alias.end
+                {
+                    break;
+                }
+                // The page also maps memory outside of the range, which should keep its memory type
+                unsafe { self.split_page(page, frame_allocator) }
+                    .map_err(MemTypeError::SplitDirectMap)?;
+            }
+        }
+        let mut addr = alias.start;
+        while addr < alias.end {
+            let mut entry = match self.leaf_entry_mut(addr) {
+                Ok(entry) => entry,
+                Err(size) => {
+                    addr = addr.align_down(size.byte_len_u64()) + size.byte_len_u64();
+                    continue;
+                }
+            };
+            let size = entry.frame().unwrap().size();
+            let page = Page::new(addr.align_down(size.byte_len_u64()), size).unwrap();
+            // The entry is known to point to a frame, so this cannot fail
+            if retype_entry(&mut entry, page, memory_type).map_err(MemTypeError::RetypeDirectMap)? {
+                entry.set_software_flags(entry.software_flags() | RETYPED_DIRECT_MAP);
+            }
+            addr = page.start_addr() + size.byte_len_u64();
+        }
+        Ok(())
+    }
+
+    fn restore_direct_map(&mut self, alias: Range<VirtAddr>) {
+        let mut addr = alias.start;
+        while addr < alias.end {
+            let mut entry = match self.leaf_entry_mut(addr) {
+                Ok(entry) => entry,
+                Err(size) => {
+                    addr = addr.align_down(size.byte_len_u64()) + size.byte_len_u64();
+                    continue;
+                }
+            };
+            let size = entry.frame().unwrap().size();
+            let page = Page::new(addr.align_down(size.byte_len_u64()), size).unwrap();
+            if entry.software_flags().contains(RETYPED_DIRECT_MAP) {
+                entry.set_software_flags(entry.software_flags() - RETYPED_DIRECT_MAP);
+                // The entry is known to point to a frame, so this cannot fail
+                let _ = retype_entry(&mut entry, page, PatMemoryType::WriteBack);
+            }
+            addr = page.start_addr() + size.byte_len_u64();
+        }
+    }
+}
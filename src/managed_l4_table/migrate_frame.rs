@@ -0,0 +1,308 @@
+use x86_64::{instructions::tlb::flush, structures::paging::PageTableFlags};
+
+use crate::*;
+
+use super::{GetTableError, ManagedL4PageTable, MemTypeError};
+
+/// Marks entries that were writable before they were write-protected for a migration
+const MIGRATING_WRITABLE: PageTableFlags = PageTableFlags::BIT_11;
+
+#[derive(Debug)]
+pub enum MigrateFrameError {
+    /// The frames have different sizes
+    SizeMismatch,
+    /// A mapping in the [`ReverseMap`] could not be found in its page table
+    GetTable(GetTableError),
+    /// A mapping in the [`ReverseMap`] does not point to the old frame anymore
+    NotMapped,
+    /// The new frame is already reserved with a different memory type than the old frame
+    MemTypeConflict,
+    /// The old frame is pinned, so it must not be moved
+    Pinned,
+    /// The [`FrameDatabase`] counts more mappings of the old frame than the [`ReverseMap`] has, such as when a huge page contains the frame
+    UntrackedMappings,
+    Copy(CopyFrameError),
+    /// Reserving the memory type of the new frame failed
+    MemType(MemTypeError),
+}
+
+/// A frame whose mappings were write-protected by [`PagingConfig::start_migration`].
+/// Finish it with [`PagingConfig::finish_migration`], or undo it with [`PagingConfig::cancel_migration`].
+#[derive(Debug)]
+#[must_use]
+pub struct PendingMigration {
+    old: Frame,
+    shootdown: TlbShootdownBatch,
+}
+
+impl PendingMigration {
+    pub fn frame(&self) -> Frame {
+        self.old
+    }
+
+    /// The pages that were write-protected, which other CPUs must flush before the frame is copied
+    pub fn shootdown(&self) -> &TlbShootdownBatch {
+        &self.shootdown
+    }
+}
+
+/// Calls `f` with the page table and page of every mapping of `frame`, until `f` fails
+fn try_for_each_mapping(
+    config: &PagingConfig,
+    reverse_map: &dyn MappingTracker,
+    frame: Frame,
+    mut f: impl FnMut(&mut ManagedL4PageTable, Page) -> Result<(), MigrateFrameError>,
+) -> Result<(), MigrateFrameError> {
+    let mut result = Ok(());
+    reverse_map.for_each_mapping(frame, &mut |mapping| {
+        if result.is_ok() {
+            let mut table = unsafe { ManagedL4PageTable::from_mapping(*config, mapping) };
+            result = f(&mut table, mapping.page);
+        }
+    });
+    result
+}
+
+/// The flags of `page`, checking that it still maps `frame`
+fn mapped_flags(
+    table: &mut ManagedL4PageTable,
+    page: Page,
+    frame: Frame,
+) -> Result<ConfigurableFlags, MigrateFrameError> {
+    let entry = table
+        .page_entry_mut(page)
+        .map_err(MigrateFrameError::GetTable)?;
+    match (entry.frame(), entry.configurable_flags()) {
+        (Some(mapped), Some(flags)) if mapped.start_addr() == frame.start_addr() => Ok(flags),
+        _ => Err(MigrateFrameError::NotMapped),
+    }
+}
+
+impl PagingConfig {
+    fn migration_reverse_map(&self) -> &'static dyn MappingTracker {
+        self.reverse_map
+            .expect("Migrating frames needs a ReverseMap attached to the PagingConfig")
+    }
+
+    /// Write-protects every page that maps `old`, which are found with the attached [`ReverseMap`], so that [`PagingConfig::finish_migration`] can copy the frame.
+    /// Something that writes to the frame during the migration will page fault, and should wait for the migration to finish and try again.
+    /// The pages are only flushed from the TLB of this CPU, and other CPUs must flush [`PendingMigration::shootdown`] before the migration is finished.
+    ///
+    /// Fails if the frame is pinned, or if the attached [`FrameDatabase`] counts mappings that are not in the reverse map.
+    /// Panics if no [`ReverseMap`] is attached.
+    ///
+    /// # Safety
+    /// All L4 tables that map the frame must still exist and must not be accessed by anything else until the migration is finished or cancelled.
+    /// The page fault handler must handle write-protected pages as described above.
+    pub unsafe fn start_migration(
+        &self,
+        old: Frame,
+    ) -> Result<PendingMigration, MigrateFrameError> {
+        let reverse_map = self.migration_reverse_map();
+        if let Some(meta) = self.frame_database.and_then(|database| database.frame(old)) {
+            if meta.flags().contains(FrameFlags::PINNED) {
+                return Err(MigrateFrameError::Pinned);
+            }
+            let mut mapping_count = 0;
+            reverse_map.for_each_mapping(old, &mut |_| mapping_count += 1);
+            if meta.map_count() != mapping_count {
+                return Err(MigrateFrameError::UntrackedMappings);
+            }
+        }
+        let mut shootdown = TlbShootdownBatch::new();
+        let result = try_for_each_mapping(self, reverse_map, old, |table, page| {
+            mapped_flags(table, page, old)?;
+            let entry = table.page_entry_mut(page).unwrap();
+            let flags = entry.entry.flags();
+            if flags.contains(PageTableFlags::WRITABLE) {
+                entry
+                    .entry
+                    .set_flags((flags - PageTableFlags::WRITABLE) | MIGRATING_WRITABLE);
+                flush(page.start_addr());
+                shootdown.push(Mapping {
+                    l4_frame: table.frame.0,
+                    page,
+                });
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            self.restore_writable(reverse_map, old);
+            return Err(e);
+        }
+        Ok(PendingMigration { old, shootdown })
+    }
+
+    /// Undoes [`PagingConfig::start_migration`], making the pages writable again
+    ///
+    /// # Safety
+    /// Same as [`PagingConfig::start_migration`]
+    pub unsafe fn cancel_migration(&self, migration: PendingMigration) {
+        self.restore_writable(self.migration_reverse_map(), migration.old);
+    }
+
+    /// Copies the frame of a migration to `new`, and makes every page that maps the old frame map `new` instead, with the same flags.
+    /// The references, flags and owner of the old frame in the attached [`FrameDatabase`] are moved to `new`.
+    /// The moved pages are flushed from the TLB of this CPU and added to `shootdown`, including when this fails after some pages were already moved.
+    /// If this fails, the pages that still map the old frame are made writable again.
+    ///
+    /// # Safety
+    /// Same as [`PagingConfig::start_migration`].
+    /// Other CPUs must have flushed [`PendingMigration::shootdown`].
+    /// `new` must be owned by the caller. After this succeeds, the old frame is given to the caller, but must not be reused until other CPUs flushed `shootdown`.
+    pub unsafe fn finish_migration(
+        &self,
+        migration: PendingMigration,
+        new: Frame,
+        shootdown: &mut TlbShootdownBatch,
+    ) -> Result<(), MigrateFrameError> {
+        let old = migration.old;
+        let reverse_map = self.migration_reverse_map();
+        let result = self
+            .check_migration_target(old, new)
+            .and_then(|()| unsafe { self.copy_frame(old, new) }.map_err(MigrateFrameError::Copy))
+            .and_then(|()| self.reserve_migration_target(reverse_map, old, new));
+        if let Err(e) = result {
+            self.restore_writable(reverse_map, old);
+            return Err(e);
+        }
+
+        while let Some(mapping) = reverse_map.first_mapping(old) {
+            let mut table = unsafe { ManagedL4PageTable::from_mapping(*self, mapping) };
+            let flags = match mapped_flags(&mut table, mapping.page, old) {
+                Ok(flags) => flags,
+                Err(e) => {
+                    // Free the reservations of the pages that were not moved
+                    let _ = try_for_each_mapping(self, reverse_map, old, |table, page| {
+                        if let Ok(flags) = mapped_flags(table, page, old) {
+                            table.untrack_mem_type(page, new, flags.pat_memory_type);
+                        }
+                        Ok(())
+                    });
+                    self.restore_writable(reverse_map, old);
+                    return Err(e);
+                }
+            };
+            let entry = table.page_entry_mut(mapping.page).unwrap();
+            let mut entry_flags = entry.entry.flags();
+            if entry_flags.contains(MIGRATING_WRITABLE) {
+                entry_flags = (entry_flags - MIGRATING_WRITABLE) | PageTableFlags::WRITABLE;
+            }
+            entry.entry.set_addr(new.start_addr(), entry_flags);
+            flush(mapping.page.start_addr());
+            table.untrack_mem_type(mapping.page, old, flags.pat_memory_type);
+            if let Some(meta) = table.frame_meta(old) {
+                meta.remove_mapping();
+            }
+            if let Some(meta) = table.frame_meta(new) {
+                meta.add_mapping();
+            }
+            // The mapping was found in the reverse map, so it is still there
+            reverse_map.move_mapping(old, new, mapping);
+            shootdown.push(mapping);
+        }
+        if let Some(database) = self.frame_database
+            && let (Some(old_meta), Some(new_meta)) = (database.frame(old), database.frame(new))
+        {
+            old_meta.move_to(new_meta);
+        }
+        Ok(())
+    }
+
+    /// Moves the contents of `old` to `new`, and makes every page that maps `old` map `new` instead, with the same flags.
+    /// This does [`PagingConfig::start_migration`] and [`PagingConfig::finish_migration`].
+    /// `flush_other_cpus` is called with the write-protected pages before the frame is copied, and with the moved pages at the end, including when moving fails,
+    /// and must flush them from the TLBs of all other CPUs before returning.
+    /// Panics if no [`ReverseMap`] is attached.
+    ///
+    /// # Safety
+    /// Same as [`PagingConfig::start_migration`].
+    /// `new` must be owned by the caller, and `old` is given to the caller after this succeeds.
+    pub unsafe fn migrate_frame(
+        &self,
+        old: Frame,
+        new: Frame,
+        flush_other_cpus: &mut impl FnMut(&TlbShootdownBatch),
+    ) -> Result<(), MigrateFrameError> {
+        let migration = unsafe { self.start_migration(old) }?;
+        if !migration.shootdown().is_empty() {
+            flush_other_cpus(migration.shootdown());
+        }
+        let mut shootdown = TlbShootdownBatch::new();
+        let result = unsafe { self.finish_migration(migration, new, &mut shootdown) };
+        if !shootdown.is_empty() {
+            flush_other_cpus(&shootdown);
+        }
+        result
+    }
+
+    fn check_migration_target(&self, old: Frame, new: Frame) -> Result<(), MigrateFrameError> {
+        if old.size() != new.size() {
+            return Err(MigrateFrameError::SizeMismatch);
+        }
+        // The frame could have been pinned for reading after it was write-protected
+        if self
+            .frame_database
+            .and_then(|database| database.frame(old))
+            .is_some_and(|meta| meta.flags().contains(FrameFlags::PINNED))
+        {
+            return Err(MigrateFrameError::Pinned);
+        }
+        if let Some(mem_types) = self.mem_types {
+            let range =
+                |frame: Frame| frame.start_addr()..frame.start_addr() + frame.size().byte_len_u64();
+            if let Some(new_type) = mem_types.database.lookup(range(new))
+                && mem_types.database.lookup(range(old)) != Some(new_type)
+            {
+                return Err(MigrateFrameError::MemTypeConflict);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reserves the memory type of `new` for every mapping of `old`, so that moving the mappings cannot fail halfway
+    fn reserve_migration_target(
+        &self,
+        reverse_map: &dyn MappingTracker,
+        old: Frame,
+        new: Frame,
+    ) -> Result<(), MigrateFrameError> {
+        let mut reserved_count = 0;
+        let result = try_for_each_mapping(self, reverse_map, old, |table, page| {
+            let flags = mapped_flags(table, page, old)?;
+            table
+                .track_mem_type_without_allocating(page, new, flags.pat_memory_type)
+                .map_err(MigrateFrameError::MemType)?;
+            reserved_count += 1;
+            Ok(())
+        });
+        if result.is_err() {
+            let mut index = 0;
+            let _ = try_for_each_mapping(self, reverse_map, old, |table, page| {
+                if index < reserved_count
+                    && let Ok(flags) = mapped_flags(table, page, old)
+                {
+                    table.untrack_mem_type(page, new, flags.pat_memory_type);
+                }
+                index += 1;
+                Ok(())
+            });
+        }
+        result
+    }
+
+    /// Makes the mappings of `frame` that were write-protected for a migration writable again
+    fn restore_writable(&self, reverse_map: &dyn MappingTracker, frame: Frame) {
+        reverse_map.for_each_mapping(frame, &mut |mapping| {
+            let mut table = unsafe { ManagedL4PageTable::from_mapping(*self, mapping) };
+            if let Ok(entry) = table.page_entry_mut(mapping.page) {
+                let flags = entry.entry.flags();
+                if flags.contains(MIGRATING_WRITABLE) {
+                    entry
+                        .entry
+                        .set_flags((flags - MIGRATING_WRITABLE) | PageTableFlags::WRITABLE);
+                }
+            }
+        });
+    }
+}
@@ -1,15 +1,52 @@
+pub use adopt::*;
 pub use configurable_flags::*;
+pub use dma::*;
+pub use fixmap::*;
+pub use handoff::*;
+pub use ioremap::*;
+pub use kernel_stack::*;
 pub use managed_l4_page_table::*;
+pub use map_owned_page::*;
 pub use map_page::*;
+pub use map_physical_memory::*;
+pub use mem_type_tracking::*;
+pub use migrate_frame::*;
 pub use page_table_entry_with_level::*;
 pub use page_table_with_level::*;
+pub use pin_user_pages::*;
+pub use pool_l4_page_table::*;
+pub use scatter_gather::*;
+pub use split_page::*;
 pub use unmap_page::*;
 pub use update_flags::*;
+pub use update_memory_type::*;
+#[cfg(feature = "elf")]
+pub use user_loader::*;
+pub use vmalloc::*;
 
+mod adopt;
 mod configurable_flags;
+mod dma;
+mod fixmap;
+mod handoff;
+mod ioremap;
+mod kernel_stack;
 mod managed_l4_page_table;
+mod map_owned_page;
 mod map_page;
+mod map_physical_memory;
+mod mem_type_tracking;
+mod migrate_frame;
 mod page_table_entry_with_level;
 mod page_table_with_level;
+mod pin_user_pages;
+mod pool_l4_page_table;
+mod scatter_gather;
+mod split_page;
+mod unmap_all;
 mod unmap_page;
 mod update_flags;
+mod update_memory_type;
+#[cfg(feature = "elf")]
+mod user_loader;
+mod vmalloc;
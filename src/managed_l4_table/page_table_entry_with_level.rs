@@ -1,7 +1,9 @@
 use core::ptr::NonNull;
 
-use x86_64::structures::paging::{
-    PageTable, PageTableFlags, PhysFrame, page_table::PageTableEntry,
+use x86_64::{
+    VirtAddr,
+    instructions::tlb::flush,
+    structures::paging::{PageTableFlags, PhysFrame, page_table::PageTableEntry},
 };
 
 use super::{ConfigurableFlags, ManagedL4PageTable, PageTableLevel, PageTableWithLevelMut};
@@ -12,6 +14,8 @@ pub struct PageTableEntryWithLevelMut<'a> {
     pub(super) entry: &'a mut PageTableEntry,
     pub(super) level: PageTableLevel,
     pub(super) l4: &'a ManagedL4PageTable,
+    /// Keeps the table containing the entry mapped in kmap mode
+    pub(super) _kmap_guard: Option<KmapGuard>,
 }
 
 #[derive(Debug)]
@@ -26,6 +30,8 @@ pub enum SetFrameError {
 pub enum SetTableError {
     /// This page table is a L1 table and L1 entries don't point to another page table.
     IsL1,
+    /// The frame is not in the direct map, so it cannot be used as a page table
+    NotDirectlyMapped,
 }
 
 #[derive(Debug)]
@@ -36,6 +42,8 @@ pub enum GetTableError {
     NotMapped,
     /// This entry is mapped, but not mapped to a table
     MappedToFrame,
+    /// The page table that this entry points to is not in the direct map
+    NotDirectlyMapped,
 }
 
 #[derive(Debug)]
@@ -52,11 +60,60 @@ pub enum SetFlagsError {
     IsPageTable,
 }
 
+#[derive(Debug)]
+pub enum SplitError {
+    /// The entry does not point to a 2 MiB or 1 GiB frame
+    NotHugePage,
+    /// The frame for the new page table is not in the direct map, or page tables are accessed recursively
+    NotDirectlyMapped,
+}
+
+/// Flags that the CPU ignores, which ez_paging uses to keep track of things in page table entries.
+/// They are kept when changing flags.
+pub(super) const SOFTWARE_FLAGS: PageTableFlags = PageTableFlags::BIT_9
+    .union(PageTableFlags::BIT_10)
+    .union(PageTableFlags::BIT_11);
+
 impl PageTableEntryWithLevelMut<'_> {
     pub fn is_empty(&self) -> bool {
         self.entry.is_unused()
     }
 
+    /// Returns the frame that this entry points to, or `None` if the entry is not present or points to a page table
+    pub fn frame(&self) -> Option<Frame> {
+        let page_size = self.page_size()?;
+        if !self.points_to_frame() {
+            return None;
+        }
+        Some(
+            Frame::new(
+                self.entry.addr().align_down(page_size.byte_len_u64()),
+                page_size,
+            )
+            .unwrap(),
+        )
+    }
+
+    /// Returns the flags of the frame that this entry points to, or `None` if the entry is not present or points to a page table
+    pub fn configurable_flags(&self) -> Option<ConfigurableFlags> {
+        let page_size = self.page_size()?;
+        if !self.points_to_frame() {
+            return None;
+        }
+        let flags = self.entry.flags();
+        Some(ConfigurableFlags {
+            writable: flags.contains(PageTableFlags::WRITABLE),
+            executable: !flags.contains(PageTableFlags::NO_EXECUTE),
+            pat_memory_type: self.l4.config.pat.get_memory_type(flags, page_size),
+        })
+    }
+
+    fn points_to_frame(&self) -> bool {
+        let flags = self.entry.flags();
+        flags.contains(PageTableFlags::PRESENT)
+            && (flags.contains(PageTableFlags::HUGE_PAGE) || self.level == PageTableLevel::L1)
+    }
+
     fn page_size(&self) -> Option<PageSize> {
         match self.level {
             PageTableLevel::L1 => Some(PageSize::_4KiB),
@@ -86,6 +143,8 @@ impl PageTableEntryWithLevelMut<'_> {
             L4Type::Kernel(_) => {
                 flags |= PageTableFlags::GLOBAL;
             }
+            // The identity maps get removed later, so they must not stay in the TLB
+            L4Type::Handoff { .. } => {}
         };
         flags
     }
@@ -126,7 +185,8 @@ impl PageTableEntryWithLevelMut<'_> {
         {
             return Err(UnmapFrameError::IsPageTable);
         }
-        let start_addr = self.entry.addr();
+        // For huge pages, the address includes the PAT bit, which is bit 12
+        let start_addr = self.entry.addr().align_down(frame_size.byte_len_u64());
         self.entry.set_unused();
         Ok(Frame::new(start_addr, frame_size).unwrap())
     }
@@ -142,13 +202,64 @@ impl PageTableEntryWithLevelMut<'_> {
         {
             return Err(SetFlagsError::IsPageTable);
         }
-        self.entry.set_flags(self.generate_flags(flags));
+        self.entry
+            .set_flags(self.generate_flags(flags) | self.software_flags());
+        Ok(())
+    }
+
+    pub(super) fn software_flags(&self) -> PageTableFlags {
+        self.entry.flags() & SOFTWARE_FLAGS
+    }
+
+    /// Replaces the software flags of an entry that is present
+    pub(super) fn set_software_flags(&mut self, software_flags: PageTableFlags) {
+        let flags = (self.entry.flags() - SOFTWARE_FLAGS) | (software_flags & SOFTWARE_FLAGS);
+        self.entry.set_flags(flags);
+    }
+
+    /// Replaces the huge page that this entry points to with a page table that maps the same frame with 512 smaller pages with the same flags.
+    /// The new page table is filled before this entry is changed, so the memory stays mapped the whole time.
+    /// The page still needs to be flushed from the TLB afterwards.
+    ///
+    /// This method overwrites `table_frame`.
+    pub fn split(&mut self, table_frame: PhysFrame) -> Result<(), SplitError> {
+        let sub_level = match self.level {
+            PageTableLevel::L3 | PageTableLevel::L2 => self.level.sub_level().unwrap(),
+            PageTableLevel::L4 | PageTableLevel::L1 => return Err(SplitError::NotHugePage),
+        };
+        let frame = self.frame().ok_or(SplitError::NotHugePage)?;
+        let sub_frame_size = sub_level.target_frame_size().unwrap();
+        let mut flags = self.entry.flags();
+        if sub_level == PageTableLevel::L1 {
+            flags.remove(PageTableFlags::HUGE_PAGE);
+            if flags.contains(PageTableFlags::PAT_HUGE_PAGE) {
+                flags.remove(PageTableFlags::PAT_HUGE_PAGE);
+                flags.insert(PageTableFlags::PAT_4KIB_PAGE);
+            }
+        }
+        let mut table_ptr = self
+            .l4
+            .config
+            .new_table_ptr(table_frame)
+            .ok_or(SplitError::NotDirectlyMapped)?;
+        let table = unsafe { table_ptr.ptr.as_mut() };
+        for (i, entry) in table.iter_mut().enumerate() {
+            entry.set_addr(
+                frame.start_addr() + i as u64 * sub_frame_size.byte_len_u64(),
+                flags,
+            );
+        }
+        self.entry.set_frame(
+            table_frame,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE,
+        );
         Ok(())
     }
 }
 
 impl<'a> PageTableEntryWithLevelMut<'a> {
-    /// This method also zeroes the frame
+    /// This method also zeroes the frame.
+    /// When using a direct map, the frame is rejected if it is not in the direct map, since it could not be accessed later.
     pub fn set_page_table(
         self,
         frame: PhysFrame,
@@ -159,23 +270,26 @@ impl<'a> PageTableEntryWithLevelMut<'a> {
                 "Cannot create new L3 pages because the kernel page table would be out of sync with user page tables"
             )
         }
-        let ptr = NonNull::new(
-            frame
-                .start_address()
-                .to_virt(&self.l4.config)
-                .as_mut_ptr::<PageTable>(),
-        )
-        .unwrap();
-        unsafe { ptr.write_bytes(0, 1) };
-
-        self.entry.set_frame(
-            frame,
-            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE,
-        );
+        let config = &self.l4.config;
+        let table_ptr = config
+            .child_table_ptr(NonNull::from(&*self.entry), frame)
+            .ok_or(SetTableError::NotDirectlyMapped)?;
+        let ptr = table_ptr.ptr;
+        let flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        if config.child_table_needs_entry() {
+            self.entry.set_frame(frame, flags);
+            flush(VirtAddr::from_ptr(ptr.as_ptr()));
+            unsafe { ptr.write_bytes(0, 1) };
+        } else {
+            unsafe { ptr.write_bytes(0, 1) };
+            self.entry.set_frame(frame, flags);
+        }
         Ok(PageTableWithLevelMut {
             page_table: ptr,
             level: page_table_level,
             l4: self.l4,
+            kmap_guard: table_ptr.kmap_guard,
         })
     }
 
@@ -188,17 +302,16 @@ impl<'a> PageTableEntryWithLevelMut<'a> {
             return Err(GetTableError::MappedToFrame);
         }
         let frame = self.entry.frame(false).unwrap();
-        let ptr = NonNull::new(
-            frame
-                .start_address()
-                .to_virt(&self.l4.config)
-                .as_mut_ptr::<PageTable>(),
-        )
-        .unwrap();
+        let table_ptr = self
+            .l4
+            .config
+            .child_table_ptr(NonNull::from(&*self.entry), frame)
+            .ok_or(GetTableError::NotDirectlyMapped)?;
         Ok(PageTableWithLevelMut {
-            page_table: ptr,
+            page_table: table_ptr.ptr,
             level: page_table_level,
             l4: self.l4,
+            kmap_guard: table_ptr.kmap_guard,
         })
     }
 }
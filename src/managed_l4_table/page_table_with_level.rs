@@ -38,6 +38,8 @@ pub struct PageTableWithLevelMut<'a> {
     pub(super) l4: &'a ManagedL4PageTable,
     pub(super) page_table: NonNull<PageTable>,
     pub(super) level: PageTableLevel,
+    /// Keeps the table mapped in kmap mode
+    pub(super) kmap_guard: Option<KmapGuard>,
 }
 
 impl<'a> PageTableWithLevelMut<'a> {
@@ -49,6 +51,11 @@ impl<'a> PageTableWithLevelMut<'a> {
                     "Cannot access L4 entry {index:?} because it is outside of the range managed by this page table ({range:?})"
                 )
             }
+            if self.l4.config.is_reserved_l4_index(index) {
+                panic!(
+                    "Cannot access L4 entry {index:?} because it is used for accessing page tables"
+                )
+            }
         }
         PageTableEntryWithLevelMut {
             entry: {
@@ -58,6 +65,7 @@ impl<'a> PageTableWithLevelMut<'a> {
             },
             level: self.level,
             l4: self.l4,
+            _kmap_guard: self.kmap_guard,
         }
     }
 }
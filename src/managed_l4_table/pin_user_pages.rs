@@ -0,0 +1,128 @@
+use core::ops::Range;
+
+use x86_64::VirtAddr;
+
+use crate::*;
+
+use super::ManagedL4PageTable;
+
+/// Lets [`ManagedL4PageTable::pin_user_pages`] fault in pages the same way the page fault handler would
+pub trait PageFaultHandler {
+    /// Handles an access to `addr` that would page fault, such as by mapping a new frame, or by copying a copy-on-write page when `write` is `true`.
+    /// Returns `false` if the access is not allowed.
+    fn handle_fault(&mut self, table: &mut ManagedL4PageTable, addr: VirtAddr, write: bool)
+    -> bool;
+}
+
+#[derive(Debug)]
+pub enum PinUserPagesError {
+    /// The address is not mapped, and could not be faulted in
+    NotMapped(VirtAddr),
+    /// The page containing the address is not writable, but `write` was `true`
+    NotWritable(VirtAddr),
+    /// The frame mapped at the address is not in the [`FrameDatabase`], so it cannot be pinned
+    NotInFrameDatabase(VirtAddr),
+    /// The range is mapped by more frames than [`PinnedPages`] can hold
+    TooManyFrames,
+}
+
+/// Frames pinned by [`ManagedL4PageTable::pin_user_pages`], which are unpinned when this is dropped
+#[derive(Debug)]
+pub struct PinnedPages<const N: usize> {
+    frame_database: &'static FrameDatabase,
+    frames: [Option<Frame>; N],
+    release: fn(Frame),
+}
+
+impl<const N: usize> PinnedPages<N> {
+    /// The pinned frames, in the order of the virtual range
+    pub fn frames(&self) -> impl Iterator<Item = &Frame> {
+        self.frames.iter().flatten()
+    }
+
+    fn push(&mut self, frame: Frame) -> Result<(), PinUserPagesError> {
+        let slot = self
+            .frames
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(PinUserPagesError::TooManyFrames)?;
+        *slot = Some(frame);
+        Ok(())
+    }
+}
+
+impl<const N: usize> Drop for PinnedPages<N> {
+    fn drop(&mut self) {
+        for frame in self.frames.iter().flatten() {
+            // Frames were only added after they were found in the database
+            if self.frame_database.frame(*frame).unwrap().unpin() {
+                (self.release)(*frame);
+            }
+        }
+    }
+}
+
+impl ManagedL4PageTable {
+    /// Pins the frames mapped in `range` in the attached [`FrameDatabase`], so that they are not released while a device accesses them.
+    /// Frames that are unmapped while pinned must be freed with [`FrameDatabase::release`], which calls `release` when the returned [`PinnedPages`] is dropped instead.
+    ///
+    /// This crate only does that where it frees the frames itself: [`ManagedL4PageTable::vfree`], [`KernelStack`], and [`DmaBuffer`].
+    /// [`ManagedL4PageTable::unmap_page`] and [`PagingConfig::unmap_all`] give the frame back to the caller, who must free it with [`FrameDatabase::release`],
+    /// and [`PagingConfig::migrate_frame`] refuses to move pinned frames.
+    ///
+    /// If a page is not mapped, or `write` is `true` and the page is not writable (such as a copy-on-write page), `fault_handler` is used to fault it in.
+    /// Without a fault handler, those pages cause an error.
+    /// Addresses in L4 entries that this page table does not manage, such as the higher half, are treated as not mapped and are not given to `fault_handler`.
+    ///
+    /// This must be a user page table.
+    /// Panics if no [`FrameDatabase`] is attached.
+    pub fn pin_user_pages<const N: usize>(
+        &mut self,
+        range: Range<VirtAddr>,
+        write: bool,
+        mut fault_handler: Option<&mut dyn PageFaultHandler>,
+        release: fn(Frame),
+    ) -> Result<PinnedPages<N>, PinUserPagesError> {
+        let frame_database = self
+            .config
+            .frame_database
+            .expect("pin_user_pages needs a FrameDatabase attached to the PagingConfig");
+        let mut pinned = PinnedPages {
+            frame_database,
+            frames: [None; N],
+            release,
+        };
+        let mut addr = range.start;
+        while addr < range.end {
+            if !self.manages_addr(addr) {
+                return Err(PinUserPagesError::NotMapped(addr));
+            }
+            let mut faulted = false;
+            let frame = loop {
+                let error = match self.leaf_entry_mut(addr) {
+                    Ok(entry) if write && !entry.configurable_flags().unwrap().writable => {
+                        PinUserPagesError::NotWritable(addr)
+                    }
+                    Ok(entry) => break entry.frame().unwrap(),
+                    Err(_) => PinUserPagesError::NotMapped(addr),
+                };
+                // Only fault once, so that a fault handler that does nothing cannot cause an infinite loop
+                let handled = !faulted
+                    && fault_handler
+                        .as_deref_mut()
+                        .is_some_and(|fault_handler| fault_handler.handle_fault(self, addr, write));
+                if !handled {
+                    return Err(error);
+                }
+                faulted = true;
+            };
+            let meta = frame_database
+                .frame(frame)
+                .ok_or(PinUserPagesError::NotInFrameDatabase(addr))?;
+            pinned.push(frame)?;
+            meta.pin();
+            addr = addr.align_down(frame.size().byte_len_u64()) + frame.size().byte_len_u64();
+        }
+        Ok(pinned)
+    }
+}
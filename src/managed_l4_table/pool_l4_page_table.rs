@@ -0,0 +1,84 @@
+use core::ops::Deref;
+
+use x86_64::structures::paging::PhysFrame;
+
+use crate::*;
+
+use super::{KernelL4Data, L4Type, MapPageError, UnmapPageError};
+
+/// A kernel page table whose page tables all come from a [`StaticTablePool`], for mapping pages before there is a frame allocator.
+/// Once there is a frame allocator, convert it with [`PoolL4PageTable::into_managed`].
+#[derive(Debug)]
+pub struct PoolL4PageTable<const N: usize> {
+    table: ManagedL4PageTable,
+    pool: &'static StaticTablePool<N>,
+}
+
+impl PagingConfig {
+    /// Like [`PagingConfig::new_kernel`], but the L4 table and all tables created later come from `pool`.
+    /// Panics if the pool is empty or not initialized.
+    pub fn new_kernel_in_pool<const N: usize>(
+        self,
+        pool: &'static StaticTablePool<N>,
+    ) -> PoolL4PageTable<N> {
+        let frame = pool.allocate().expect("The pool has no tables left");
+        let mut table = self.new_kernel(unsafe { Owned4KibFrame::new(frame) });
+        if let L4Type::Kernel(KernelL4Data { static_tables, .. }) = &mut table._type {
+            *static_tables = Some(pool.frames());
+        }
+        PoolL4PageTable { table, pool }
+    }
+}
+
+impl<const N: usize> PoolL4PageTable<N> {
+    /// Same as [`ManagedL4PageTable::map_page`], with the pool as the frame allocator
+    ///
+    /// # Safety
+    /// Same as [`ManagedL4PageTable::map_page`]
+    pub unsafe fn map_page(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        flags: ConfigurableFlags,
+    ) -> Result<(), MapPageError> {
+        unsafe { self.table.map_page(page, frame, flags, &mut self.pool) }
+    }
+
+    /// # Safety
+    /// Same as [`ManagedL4PageTable::unmap_page`]
+    pub unsafe fn unmap_page(&mut self, page: Page) -> Result<Frame, UnmapPageError> {
+        unsafe { self.table.unmap_page(page) }
+    }
+
+    pub fn pool(&self) -> &'static StaticTablePool<N> {
+        self.pool
+    }
+
+    /// Converts this into a normal page table, which uses other frame allocators for new tables.
+    /// The page table keeps track of the pool's frames, see [`ManagedL4PageTable::is_static_table`].
+    pub fn into_managed(self) -> ManagedL4PageTable {
+        self.table
+    }
+}
+
+impl<const N: usize> Deref for PoolL4PageTable<N> {
+    type Target = ManagedL4PageTable;
+
+    fn deref(&self) -> &Self::Target {
+        &self.table
+    }
+}
+
+impl ManagedL4PageTable {
+    /// Whether `frame` is from the [`StaticTablePool`] that this page table was created in.
+    /// Such page tables must never be given to a frame deallocator, even if they become empty.
+    pub fn is_static_table(&self, frame: PhysFrame) -> bool {
+        match &self._type {
+            L4Type::Kernel(KernelL4Data {
+                static_tables: Some(static_tables),
+                ..
+            }) => static_tables.start <= frame && frame < static_tables.end,
+            _ => false,
+        }
+    }
+}
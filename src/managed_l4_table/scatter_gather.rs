@@ -0,0 +1,99 @@
+use core::ops::Range;
+
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::ManagedL4PageTable;
+
+#[derive(Debug)]
+pub enum ScatterGatherError {
+    /// The address is not mapped
+    NotMapped(VirtAddr),
+    /// The page containing the address is not writable, but `write` was `true`
+    NotWritable(VirtAddr),
+}
+
+/// The physical segments behind a virtual range, returned by [`ManagedL4PageTable::scatter_gather`]
+#[derive(Debug)]
+pub struct ScatterGather<'a> {
+    table: &'a mut ManagedL4PageTable,
+    next: VirtAddr,
+    end: VirtAddr,
+    max_segment_len: u64,
+}
+
+impl ManagedL4PageTable {
+    /// Returns the physical address of `addr` and the number of bytes after it that are in the same page.
+    /// Addresses in L4 entries that this page table does not manage are treated as not mapped.
+    fn translate_chunk(
+        &mut self,
+        addr: VirtAddr,
+        write: bool,
+    ) -> Result<(PhysAddr, u64), ScatterGatherError> {
+        if !self.manages_addr(addr) {
+            return Err(ScatterGatherError::NotMapped(addr));
+        }
+        let entry = self
+            .leaf_entry_mut(addr)
+            .map_err(|_| ScatterGatherError::NotMapped(addr))?;
+        let frame = entry.frame().unwrap();
+        if write && !entry.configurable_flags().unwrap().writable {
+            return Err(ScatterGatherError::NotWritable(addr));
+        }
+        let offset = addr - addr.align_down(frame.size().byte_len_u64());
+        Ok((
+            frame.start_addr() + offset,
+            frame.size().byte_len_u64() - offset,
+        ))
+    }
+
+    /// Returns the physical segments behind `virt_range` as `(start, len)`, for giving a buffer to a device that supports scatter-gather DMA.
+    /// Pages that are next to each other in physical memory are merged into one segment, whatever their page size is, but segments are never longer than `max_segment_len`.
+    ///
+    /// The whole range is checked first, and an error is returned if any of it is not mapped, or is not writable when `write` is `true`.
+    pub fn scatter_gather(
+        &mut self,
+        virt_range: Range<VirtAddr>,
+        max_segment_len: u64,
+        write: bool,
+    ) -> Result<ScatterGather<'_>, ScatterGatherError> {
+        assert!(
+            max_segment_len > 0,
+            "The maximum segment length must not be 0"
+        );
+        let mut addr = virt_range.start;
+        while addr < virt_range.end {
+            let (_, len) = self.translate_chunk(addr, write)?;
+            addr += len;
+        }
+        Ok(ScatterGather {
+            table: self,
+            next: virt_range.start,
+            end: virt_range.end,
+            max_segment_len,
+        })
+    }
+}
+
+impl Iterator for ScatterGather<'_> {
+    type Item = (PhysAddr, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.end {
+            return None;
+        }
+        let mut segment: Option<(PhysAddr, u64)> = None;
+        while self.next < self.end {
+            // The range was checked when creating this iterator
+            let (phys_addr, len) = self.table.translate_chunk(self.next, false).unwrap();
+            let len = len.min(self.end - self.next);
+            let (start, segment_len) = *segment.get_or_insert((phys_addr, 0));
+            if start + segment_len != phys_addr || segment_len == self.max_segment_len {
+                break;
+            }
+            let len = len.min(self.max_segment_len - segment_len);
+            segment = Some((start, segment_len + len));
+            self.next += len;
+        }
+        segment
+    }
+}
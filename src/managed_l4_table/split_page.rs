@@ -0,0 +1,51 @@
+use x86_64::{
+    instructions::tlb::flush,
+    structures::paging::{FrameAllocator, Size4KiB},
+};
+
+use crate::*;
+
+use super::{GetTableError, ManagedL4PageTable, OWNED_FRAME, SplitError};
+
+#[derive(Debug)]
+pub enum SplitPageError {
+    GetTable(GetTableError),
+    FrameAllocationFailed,
+    Split(SplitError),
+    /// The page is mapped to an [`OwnedFrame`], which must be unmapped as a whole
+    Owned,
+}
+
+impl ManagedL4PageTable {
+    /// Splits a 2 MiB or 1 GiB page into 512 smaller pages, keeping the frame and flags.
+    /// Also does `invlpg` after successfully splitting.
+    ///
+    /// # Safety
+    /// Other CPUs could still have the huge page in their TLBs until they flush it.
+    pub unsafe fn split_page(
+        &mut self,
+        page: Page,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<(), SplitPageError> {
+        let mut entry = self
+            .page_entry_mut(page)
+            .map_err(SplitPageError::GetTable)?;
+        if entry.frame().is_none() || matches!(page.size(), PageSize::_4KiB) {
+            return Err(SplitPageError::Split(SplitError::NotHugePage));
+        }
+        if entry.software_flags().contains(OWNED_FRAME) {
+            return Err(SplitPageError::Owned);
+        }
+        let table_frame = frame_allocator
+            .allocate_frame()
+            .ok_or(SplitPageError::FrameAllocationFailed)?;
+        let config = entry.l4.config;
+        config.mark_page_table(table_frame, true);
+        entry.split(table_frame).map_err(|e| {
+            config.mark_page_table(table_frame, false);
+            SplitPageError::Split(e)
+        })?;
+        flush(page.start_addr());
+        Ok(())
+    }
+}
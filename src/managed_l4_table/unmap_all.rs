@@ -0,0 +1,55 @@
+use crate::*;
+
+use super::{KernelL4Data, L4Type, ManagedL4PageTable};
+
+impl ManagedL4PageTable {
+    /// Accesses the L4 table that a [`Mapping`] from a [`ReverseMap`] is in, such as with [`ReverseMap::for_each_mapping`].
+    /// Mappings in the higher half belong to a kernel page table, and mappings in the lower half belong to a user page table.
+    /// The returned page table can only change the half that the mapping is in, and cannot create new L4 entries in the higher half.
+    ///
+    /// # Safety
+    /// `config` must be the [`PagingConfig`] of the page table that the mapping is in.
+    /// The L4 table must still exist while the returned page table is used, and must not be accessed through anything else at the same time.
+    pub unsafe fn from_mapping(config: PagingConfig, mapping: Mapping) -> Self {
+        let _type = if u16::from(mapping.page.start_addr().p4_index()) >= 256 {
+            // Referenced so that no new L4 entries are created, which would not be shared with user page tables
+            L4Type::Kernel(KernelL4Data {
+                is_referenced: true,
+                static_tables: None,
+            })
+        } else {
+            L4Type::User
+        };
+        Self {
+            frame: unsafe { Owned4KibFrame::new(mapping.l4_frame) },
+            _type,
+            config,
+        }
+    }
+}
+
+impl PagingConfig {
+    /// Unmaps every page that maps `frame`, using the attached [`ReverseMap`].
+    /// The frame is not freed, and could be pinned, so free it with [`FrameDatabase::release`] if a [`FrameDatabase`] is used.
+    /// The pages that other CPUs need to flush from their TLBs are added to `shootdown`, including when unmapping a later page fails.
+    /// Panics if no [`ReverseMap`] is attached.
+    ///
+    /// # Safety
+    /// All L4 tables that map the frame must still exist and must not be accessed by anything else while this runs.
+    /// Same as [`ManagedL4PageTable::unmap_page`] for every page.
+    pub unsafe fn unmap_all(
+        &self,
+        frame: Frame,
+        shootdown: &mut TlbShootdownBatch,
+    ) -> Result<(), UnmapPageError> {
+        let reverse_map = self
+            .reverse_map
+            .expect("unmap_all needs a ReverseMap attached to the PagingConfig");
+        while let Some(mapping) = reverse_map.first_mapping(frame) {
+            let mut table = unsafe { ManagedL4PageTable::from_mapping(*self, mapping) };
+            unsafe { table.unmap_page(mapping.page) }?;
+            shootdown.push(mapping);
+        }
+        Ok(())
+    }
+}
@@ -2,21 +2,41 @@ use x86_64::instructions::tlb::flush;
 
 use crate::*;
 
-use super::{GetTableError, UnmapFrameError};
+use super::{GetTableError, OWNED_FRAME, UnmapFrameError};
 
 #[derive(Debug)]
 pub enum UnmapPageError {
     GetTable(GetTableError),
     UnmapFrame(UnmapFrameError),
+    /// The page is mapped to an [`OwnedFrame`], so it must be unmapped with [`ManagedL4PageTable::unmap_owned_page`]
+    Owned,
 }
 
 impl ManagedL4PageTable {
     /// Also does `invlpg` after successfully un-mapping.
     /// Returns the entry that was removed.
+    /// If a [`MemTypeDatabase`] is attached to the [`PagingConfig`], the frame's memory type reservation is freed.
+    /// If a [`FrameDatabase`] is attached, the frame's map count is decreased.
+    /// If a [`ReverseMap`] is attached, the mapping is removed from it.
+    /// The frame is not freed. It could be pinned with [`ManagedL4PageTable::pin_user_pages`], so if a [`FrameDatabase`] is used,
+    /// free it with [`FrameDatabase::release`], or a device could still be accessing it after it is reused.
     ///
     /// # Safety
     /// Don't unmap the wrong thing. It can cause page faults.
     pub unsafe fn unmap_page(&mut self, page: Page) -> Result<Frame, UnmapPageError> {
+        let (frame, flags) = unsafe { self.unmap_page_untracked(page) }?;
+        if let Some(flags) = flags {
+            self.untrack_mem_type(page, frame, flags.pat_memory_type);
+        }
+        Ok(frame)
+    }
+
+    /// Same as [`ManagedL4PageTable::unmap_page`], but the memory type reservation is not freed.
+    /// Also returns the flags that the page was mapped with.
+    pub(super) unsafe fn unmap_page_untracked(
+        &mut self,
+        page: Page,
+    ) -> Result<(Frame, Option<ConfigurableFlags>), UnmapPageError> {
         let l4 = self.table_mut();
         let l3 = l4
             .entry_mut(page.start_addr().p4_index())
@@ -40,8 +60,24 @@ impl ManagedL4PageTable {
                 l1.entry_mut(page.start_addr().p1_index())
             }
         };
+        if entry.software_flags().contains(OWNED_FRAME) {
+            return Err(UnmapPageError::Owned);
+        }
+        let flags = entry.configurable_flags();
         let frame = entry.unmap_frame().map_err(UnmapPageError::UnmapFrame)?;
         flush(page.start_addr());
-        Ok(frame)
+        if let Some(meta) = self.frame_meta(frame) {
+            meta.remove_mapping();
+        }
+        if let Some(reverse_map) = self.config.reverse_map {
+            reverse_map.remove(
+                frame,
+                Mapping {
+                    l4_frame: self.frame.0,
+                    page,
+                },
+            );
+        }
+        Ok((frame, flags))
     }
 }
@@ -2,15 +2,19 @@ use x86_64::instructions::tlb::flush;
 
 use crate::*;
 
-use super::{GetTableError, ManagedL4PageTable, SetFlagsError};
+use super::{GetTableError, ManagedL4PageTable, MemTypeError, NoFrameAllocator, SetFlagsError};
 
 #[derive(Debug)]
 pub enum UpdateFlagsError {
     GetTable(GetTableError),
     SetFlags(SetFlagsError),
+    MemType(MemTypeError),
 }
 
 impl ManagedL4PageTable {
+    /// If a [`MemTypeDatabase`] is attached and the memory type changes, the reservation is moved to the new memory type.
+    /// Unlike [`ManagedL4PageTable::update_memory_type`], this does not flush the frame from the caches.
+    ///
     /// # Safety
     /// Changing flags could cause page faults, or worse, let user mode access memory it shouldn't be allowed to.
     pub unsafe fn update_flags(
@@ -18,31 +22,49 @@ impl ManagedL4PageTable {
         page: Page,
         flags: ConfigurableFlags,
     ) -> Result<(), UpdateFlagsError> {
-        let l4 = self.table_mut();
-        let l3 = l4
-            .entry_mut(page.start_addr().p4_index())
-            .get_page_table_mut()
+        let entry = self
+            .page_entry_mut(page)
             .map_err(UpdateFlagsError::GetTable)?;
-        let l3_entry = l3.entry_mut(page.start_addr().p3_index());
-        let mut entry: super::PageTableEntryWithLevelMut<'_> = if let PageSize::_1GiB = page.size()
-        {
-            l3_entry
-        } else {
-            let l2 = l3_entry
-                .get_page_table_mut()
-                .map_err(UpdateFlagsError::GetTable)?;
-            let l2_entry = l2.entry_mut(page.start_addr().p2_index());
-            if let PageSize::_2MiB = page.size() {
-                l2_entry
-            } else {
-                let l1 = l2_entry
-                    .get_page_table_mut()
-                    .map_err(UpdateFlagsError::GetTable)?;
-                l1.entry_mut(page.start_addr().p1_index())
+        let memory_type_change = match (entry.frame(), entry.configurable_flags()) {
+            (Some(frame), Some(old_flags))
+                if old_flags.pat_memory_type != flags.pat_memory_type =>
+            {
+                Some((frame, old_flags.pat_memory_type))
             }
+            _ => None,
         };
-        entry.set_flags(flags).map_err(UpdateFlagsError::SetFlags)?;
-        flush(page.start_addr());
-        Ok(())
+        if let Some((frame, old_memory_type)) = memory_type_change {
+            self.retrack_mem_type(
+                page,
+                frame,
+                old_memory_type,
+                flags.pat_memory_type,
+                1,
+                &mut NoFrameAllocator,
+            )
+            .map_err(UpdateFlagsError::MemType)?;
+        }
+        let result = self
+            .page_entry_mut(page)
+            .unwrap()
+            .set_flags(flags)
+            .map_err(UpdateFlagsError::SetFlags);
+        match result {
+            Ok(()) => flush(page.start_addr()),
+            Err(_) => {
+                if let Some((frame, old_memory_type)) = memory_type_change {
+                    // Moving the reservation back cannot conflict, since it was just moved from there
+                    let _ = self.retrack_mem_type(
+                        page,
+                        frame,
+                        flags.pat_memory_type,
+                        old_memory_type,
+                        1,
+                        &mut NoFrameAllocator,
+                    );
+                }
+            }
+        }
+        result
     }
 }
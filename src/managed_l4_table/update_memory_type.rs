@@ -0,0 +1,201 @@
+use x86_64::{instructions::tlb::flush, registers::model_specific::PatMemoryType};
+
+use crate::*;
+
+use super::{
+    GetTableError, ManagedL4PageTable, MemTypeError, NoFrameAllocator, PageTableEntryWithLevelMut,
+    SetFlagsError,
+};
+
+#[derive(Debug)]
+pub enum UpdateMemoryTypeError {
+    GetTable(GetTableError),
+    /// The page is not mapped to a frame
+    NotMapped,
+    MemType(MemTypeError),
+    SetFlags(SetFlagsError),
+    /// There is no [`ReverseMap`] attached, so the other mappings of the frame cannot be changed too,
+    /// or there is no [`MemTypeDatabase`] attached, so the frame's alias in the direct map cannot be changed
+    UntrackedAliases,
+}
+
+/// Changes the memory type of the entry, then flushes the page from the TLB and the frame from the caches.
+/// Returns `false` if the entry already had the memory type.
+pub(super) fn retype_entry(
+    entry: &mut PageTableEntryWithLevelMut,
+    page: Page,
+    memory_type: PatMemoryType,
+) -> Result<bool, SetFlagsError> {
+    let (Some(frame), Some(flags)) = (entry.frame(), entry.configurable_flags()) else {
+        return Err(SetFlagsError::NotPresent);
+    };
+    if flags.pat_memory_type == memory_type {
+        return Ok(false);
+    }
+    entry.set_flags(ConfigurableFlags {
+        pat_memory_type: memory_type,
+        ..flags
+    })?;
+    flush(page.start_addr());
+    flush_phys_range(
+        frame.start_addr(),
+        frame.size().byte_len_u64(),
+        &entry.l4.config,
+    );
+    Ok(true)
+}
+
+impl ManagedL4PageTable {
+    /// Calls `f` with the page table and page of every mapping of `frame` in `reverse_map`, and of `page` in this page table if it is not in the reverse map
+    fn for_each_alias(
+        &mut self,
+        reverse_map: &dyn MappingTracker,
+        frame: Frame,
+        page: Page,
+        f: &mut dyn FnMut(&mut ManagedL4PageTable, Page),
+    ) {
+        let this = Mapping {
+            l4_frame: self.frame.0,
+            page,
+        };
+        let mut found_this = false;
+        reverse_map.for_each_mapping(frame, &mut |mapping| {
+            if mapping.l4_frame == self.frame.0 {
+                found_this |= mapping == this;
+                f(self, mapping.page);
+            } else {
+                f(
+                    &mut unsafe { ManagedL4PageTable::from_mapping(self.config, mapping) },
+                    mapping.page,
+                );
+            }
+        });
+        if !found_this {
+            f(self, page);
+        }
+    }
+
+    /// Changes the caching memory type of a mapped page, and of every other page that maps the same frame, keeping their other flags.
+    /// The other mappings are found with the attached [`ReverseMap`], so this fails with [`UpdateMemoryTypeError::UntrackedAliases`] without one.
+    ///
+    /// Changing the memory type is only safe if no cache lines with the old memory type are left behind.
+    /// This is done in the order described in Intel SDM -> Volume 3 -> 13.12.4 Programming the PAT:
+    /// 1. The page table entries are changed to the new memory type
+    /// 2. The pages are flushed from the TLB with `invlpg`, so that this CPU stops accessing them with the old memory type
+    /// 3. The cache lines of the frame are written back and invalidated with `clflush` through the direct map, or with `wbinvd` if that isn't possible
+    ///
+    /// If a [`MemTypeDatabase`] is attached, the reservations are moved to the new memory type, and the frame's alias in the direct map is changed too if needed.
+    /// Pages in the direct map are not split, so this fails if the direct map would need to be split.
+    /// Without a [`MemTypeDatabase`], this fails with [`UpdateMemoryTypeError::UntrackedAliases`] if the frame has an alias in the direct map that is not one of the mappings in the [`ReverseMap`].
+    /// Returns the pages that other CPUs need to flush from their TLBs.
+    ///
+    /// # Safety
+    /// Same as [`ManagedL4PageTable::update_flags`].
+    /// All L4 tables that map the frame must still exist and must not be accessed by anything else while this runs.
+    /// Other CPUs must flush the pages in the returned batch before the frame is accessed with the new memory type.
+    /// If `wbinvd` is used, it only writes back the caches of this CPU.
+    pub unsafe fn update_memory_type(
+        &mut self,
+        page: Page,
+        memory_type: PatMemoryType,
+    ) -> Result<TlbShootdownBatch, UpdateMemoryTypeError> {
+        let entry = self
+            .page_entry_mut(page)
+            .map_err(UpdateMemoryTypeError::GetTable)?;
+        let (Some(frame), Some(flags)) = (entry.frame(), entry.configurable_flags()) else {
+            return Err(UpdateMemoryTypeError::NotMapped);
+        };
+        let old_memory_type = flags.pat_memory_type;
+        if old_memory_type == memory_type {
+            return Ok(TlbShootdownBatch::new());
+        }
+        let reverse_map = self
+            .config
+            .reverse_map
+            .ok_or(UpdateMemoryTypeError::UntrackedAliases)?;
+
+        // Pages in the direct map are not reserved, so they are not counted
+        let direct_map_addr = frame.start_addr().to_virt(&self.config);
+        let mut reserved_count = 0;
+        let mut reserved_page = page;
+        let mut found_direct_map = false;
+        self.for_each_alias(reverse_map, frame, page, &mut |_, alias| {
+            if Some(alias.start_addr()) != direct_map_addr {
+                reserved_count += 1;
+                reserved_page = alias;
+            } else {
+                found_direct_map = true;
+            }
+        });
+        // Only the memory type tracking changes the direct map when it is not one of the mappings
+        if self.config.mem_types.is_none() && direct_map_addr.is_some() && !found_direct_map {
+            return Err(UpdateMemoryTypeError::UntrackedAliases);
+        }
+        self.retrack_mem_type(
+            reserved_page,
+            frame,
+            old_memory_type,
+            memory_type,
+            reserved_count,
+            &mut NoFrameAllocator,
+        )
+        .map_err(UpdateMemoryTypeError::MemType)?;
+
+        let mut batch = TlbShootdownBatch::new();
+        let mut result = Ok(());
+        self.for_each_alias(reverse_map, frame, page, &mut |table, alias| {
+            if result.is_err() {
+                return;
+            }
+            result = retype_alias(table, alias, frame, memory_type);
+            batch.push(Mapping {
+                l4_frame: table.frame.0,
+                page: alias,
+            });
+        });
+        if let Err(e) = result {
+            // Change the pages that were already changed back, so that all of them have the old memory type again
+            self.for_each_alias(reverse_map, frame, page, &mut |table, alias| {
+                let _ = retype_alias(table, alias, frame, old_memory_type);
+            });
+            // Moving the reservations back cannot conflict, since they were just moved from there
+            let _ = self.retrack_mem_type(
+                reserved_page,
+                frame,
+                memory_type,
+                old_memory_type,
+                reserved_count,
+                &mut NoFrameAllocator,
+            );
+            return Err(e);
+        }
+        if let Some(Ok(alias)) = direct_map_addr.map(|addr| Page::new(addr, frame.size())) {
+            // The direct map could have been changed by the memory type tracking
+            batch.push(Mapping {
+                l4_frame: self.frame.0,
+                page: alias,
+            });
+        }
+        Ok(batch)
+    }
+}
+
+/// Changes the memory type of `page` in `table` if it still maps `frame`
+fn retype_alias(
+    table: &mut ManagedL4PageTable,
+    page: Page,
+    frame: Frame,
+    memory_type: PatMemoryType,
+) -> Result<(), UpdateMemoryTypeError> {
+    let mut entry = table
+        .page_entry_mut(page)
+        .map_err(UpdateMemoryTypeError::GetTable)?;
+    if entry
+        .frame()
+        .is_none_or(|mapped| mapped.start_addr() != frame.start_addr())
+    {
+        return Err(UpdateMemoryTypeError::NotMapped);
+    }
+    retype_entry(&mut entry, page, memory_type).map_err(UpdateMemoryTypeError::SetFlags)?;
+    Ok(())
+}
@@ -0,0 +1,398 @@
+use x86_64::{
+    VirtAddr,
+    registers::model_specific::PatMemoryType,
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+};
+
+use crate::*;
+
+use super::{ConfigurableFlags, L4Type, ManagedL4PageTable, MapPageError};
+
+const ET_EXEC: u16 = 2;
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_FLAGS: u64 = 8;
+const AT_ENTRY: u64 = 9;
+const AT_SECURE: u64 = 23;
+const AT_RANDOM: u64 = 25;
+
+/// Where to load an ELF
+#[derive(Debug, Clone, Copy)]
+pub enum LoadBase {
+    /// Added to every virtual address in the ELF. Must be 0 for ELFs that are not position independent.
+    Fixed(u64),
+    /// A 2 MiB aligned base of at least `min`, chosen using `entropy`, so that the whole image ends at or below `max`
+    Random {
+        min: VirtAddr,
+        max: VirtAddr,
+        entropy: u64,
+    },
+}
+
+impl LoadBase {
+    /// Returns `None` if there is no random base where an image ending at `image_end` (relative to the base) fits
+    fn resolve(self, image_end: u64) -> Option<u64> {
+        match self {
+            Self::Fixed(base) => Some(base),
+            Self::Random { min, max, entropy } => {
+                let align = PageSize::_2MiB.byte_len_u64();
+                let min = min.align_up(align).as_u64();
+                let last = max
+                    .as_u64()
+                    .checked_sub(image_end)
+                    .filter(|last| *last >= min)?;
+                let slots = (last - min) / align + 1;
+                Some(min + entropy % slots * align)
+            }
+        }
+    }
+}
+
+/// The initial stack of a program, which must be in the lower half. All sizes must be multiples of 4 KiB.
+#[derive(Debug, Clone, Copy)]
+pub struct UserStack {
+    /// The end of the stack
+    pub top: VirtAddr,
+    pub size: u64,
+    /// The size of the unmapped region below the stack
+    pub guard_size: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct UserProgram<'a> {
+    pub executable: &'a [u8],
+    pub executable_base: LoadBase,
+    /// The dynamic linker, and where to load it. See [`elf_interpreter`].
+    pub interpreter: Option<(&'a [u8], LoadBase)>,
+    /// The arguments, without NUL terminators
+    pub argv: &'a [&'a [u8]],
+    /// The environment variables, without NUL terminators
+    pub envp: &'a [&'a [u8]],
+    /// Put on the stack for `AT_RANDOM`
+    pub random_bytes: [u8; 16],
+    pub stack: UserStack,
+}
+
+#[derive(Debug)]
+pub struct LoadedUserProgram {
+    /// Where to start executing, which is the interpreter's entry point if there is an interpreter
+    pub entry_point: VirtAddr,
+    /// The initial value of `rsp`, which points to `argc`
+    pub stack_pointer: VirtAddr,
+    /// The initial program break, which is right after the executable's last segment
+    pub program_break: VirtAddr,
+    pub executable: MappedElf,
+    pub interpreter: Option<MappedElf>,
+}
+
+#[derive(Debug)]
+pub enum LoadUserProgramError {
+    Executable(MapElfError),
+    Interpreter(MapElfError),
+    /// The executable has no `PT_PHDR` header, and its program headers are not in a `PT_LOAD` segment, so they cannot be found with `AT_PHDR`
+    ProgramHeadersNotLoaded,
+    /// The load base is not 0, but the ELF is not position independent
+    NotRelocatable,
+    /// There is no random load base between `min` and `max` where the whole ELF fits
+    NoRoomForImage,
+    /// The stack or its guard pages are not in the lower half, or its sizes are not multiples of 4 KiB, or it is empty
+    InvalidStack,
+    /// Something is already mapped in the stack or its guard pages
+    StackNotFree,
+    /// The arguments, environment variables, and auxiliary vector do not fit in the stack
+    StackTooSmall,
+    FrameAllocationFailed,
+    /// A frame from the frame allocator is not in the direct map
+    NotDirectlyMapped,
+    MapStack(MapPageError),
+}
+
+/// Chooses the load bias of an ELF, checking that it can be relocated if needed
+fn load_bias(elf_bytes: &[u8], base: LoadBase) -> Result<u64, LoadUserProgramError> {
+    let mut image_end = 0;
+    for header in program_headers(elf_bytes).map_err(LoadUserProgramError::Executable)? {
+        let header = header.map_err(LoadUserProgramError::Executable)?;
+        if header.p_type == PT_LOAD {
+            let end = header
+                .p_vaddr
+                .checked_add(header.p_memsz)
+                .ok_or(LoadUserProgramError::Executable(MapElfError::Malformed))?;
+            image_end = image_end.max(end);
+        }
+    }
+    let base = base
+        .resolve(image_end)
+        .ok_or(LoadUserProgramError::NoRoomForImage)?;
+    let is_executable =
+        read_u16(elf_bytes, 0x10).map_err(LoadUserProgramError::Executable)? == ET_EXEC;
+    if is_executable && base != 0 {
+        return Err(LoadUserProgramError::NotRelocatable);
+    }
+    Ok(base)
+}
+
+/// The address of the program headers in memory, for `AT_PHDR`
+fn phdr_addr(elf_bytes: &[u8], load_bias: u64) -> Result<u64, LoadUserProgramError> {
+    let ph_offset = read_u64(elf_bytes, 0x20).map_err(LoadUserProgramError::Executable)?;
+    let mut containing_load = None;
+    for header in program_headers(elf_bytes).map_err(LoadUserProgramError::Executable)? {
+        let header = header.map_err(LoadUserProgramError::Executable)?;
+        if header.p_type == PT_PHDR {
+            return Ok(header.p_vaddr.wrapping_add(load_bias));
+        }
+        if header.p_type == PT_LOAD
+            && containing_load.is_none()
+            && header
+                .p_offset
+                .checked_add(header.p_filesz)
+                .is_some_and(|end| (header.p_offset..end).contains(&ph_offset))
+        {
+            containing_load = header.p_vaddr.checked_add(ph_offset - header.p_offset);
+        }
+    }
+    containing_load
+        .map(|addr| addr.wrapping_add(load_bias))
+        .ok_or(LoadUserProgramError::ProgramHeadersNotLoaded)
+}
+
+impl ManagedL4PageTable {
+    /// Maps an executable and its interpreter, and creates the initial stack like Linux does on x86_64.
+    /// The stack has `argc`, `argv`, `envp`, and an auxiliary vector, and is not executable.
+    /// Panics if this is not a user page table.
+    ///
+    /// # Safety
+    /// Same as [`ManagedL4PageTable::map_page`]
+    pub unsafe fn load_user_program(
+        &mut self,
+        program: &UserProgram,
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    ) -> Result<LoadedUserProgram, LoadUserProgramError> {
+        if !matches!(self._type, L4Type::User) {
+            panic!("User programs can only be loaded in user page tables");
+        }
+        let executable_bias = load_bias(program.executable, program.executable_base)?;
+        let executable =
+            unsafe { self.map_elf_segments(program.executable, executable_bias, frame_allocator) }
+                .map_err(LoadUserProgramError::Executable)?;
+        let (interpreter, interpreter_bias) = match program.interpreter {
+            Some((interpreter_bytes, base)) => {
+                let bias = load_bias(interpreter_bytes, base).map_err(|e| match e {
+                    LoadUserProgramError::Executable(e) => LoadUserProgramError::Interpreter(e),
+                    e => e,
+                })?;
+                let interpreter =
+                    unsafe { self.map_elf_segments(interpreter_bytes, bias, frame_allocator) }
+                        .map_err(LoadUserProgramError::Interpreter)?;
+                (Some(interpreter), bias)
+            }
+            None => (None, 0),
+        };
+        let program_break = executable
+            .segments()
+            .map(|segment| segment.end)
+            .max()
+            .unwrap_or(VirtAddr::zero());
+        let stack = unsafe { self.create_user_stack(program, frame_allocator) }?;
+        let auxv = [
+            (AT_PHDR, phdr_addr(program.executable, executable_bias)?),
+            (
+                AT_PHENT,
+                u64::from(
+                    read_u16(program.executable, 0x36).map_err(LoadUserProgramError::Executable)?,
+                ),
+            ),
+            (
+                AT_PHNUM,
+                u64::from(
+                    read_u16(program.executable, 0x38).map_err(LoadUserProgramError::Executable)?,
+                ),
+            ),
+            (AT_PAGESZ, PageSize::_4KiB.byte_len_u64()),
+            (AT_BASE, interpreter_bias),
+            (AT_FLAGS, 0),
+            (AT_ENTRY, executable.entry_point.as_u64()),
+            (AT_SECURE, 0),
+            (AT_RANDOM, stack.random_bytes.as_u64()),
+            (AT_NULL, 0),
+        ];
+        let stack_pointer = self.write_stack_vectors(program, stack, &auxv)?;
+        Ok(LoadedUserProgram {
+            entry_point: interpreter
+                .as_ref()
+                .map_or(executable.entry_point, |interpreter| {
+                    interpreter.entry_point
+                }),
+            stack_pointer,
+            program_break,
+            executable,
+            interpreter,
+        })
+    }
+
+    /// Maps the stack, and writes the random bytes and strings at the top of it
+    unsafe fn create_user_stack(
+        &mut self,
+        program: &UserProgram,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<StackStrings, LoadUserProgramError> {
+        let stack = program.stack;
+        let page_size = PageSize::_4KiB.byte_len_u64();
+        // The top can be the end of the lower half
+        let lower_half_end = 0x0000_8000_0000_0000;
+        let guard_bottom = stack
+            .top
+            .as_u64()
+            .checked_sub(stack.size)
+            .and_then(|bottom| bottom.checked_sub(stack.guard_size))
+            .filter(|_| {
+                stack.top.as_u64() <= lower_half_end
+                    && stack.top.is_aligned(page_size)
+                    && stack.size > 0
+                    && stack.size.is_multiple_of(page_size)
+                    && stack.guard_size.is_multiple_of(page_size)
+            })
+            .ok_or(LoadUserProgramError::InvalidStack)?;
+        let bottom = stack.top - stack.size;
+        let mut addr = VirtAddr::new(guard_bottom);
+        while addr < stack.top {
+            match self.leaf_entry_mut(addr) {
+                Ok(_) => return Err(LoadUserProgramError::StackNotFree),
+                Err(unmapped_size) => {
+                    addr = addr.align_down(unmapped_size.byte_len_u64())
+                        + unmapped_size.byte_len_u64();
+                }
+            }
+        }
+        let flags = ConfigurableFlags {
+            writable: true,
+            executable: false,
+            pat_memory_type: PatMemoryType::WriteBack,
+        };
+        let mut page_addr = bottom;
+        while page_addr < stack.top {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(LoadUserProgramError::FrameAllocationFailed)?;
+            let frame = Frame::new(frame.start_address(), PageSize::_4KiB).unwrap();
+            unsafe { self.config.zero_frame(frame) }
+                .map_err(|_| LoadUserProgramError::NotDirectlyMapped)?;
+            unsafe {
+                self.map_page(
+                    Page::new(page_addr, PageSize::_4KiB).unwrap(),
+                    frame,
+                    flags,
+                    frame_allocator,
+                )
+            }
+            .map_err(LoadUserProgramError::MapStack)?;
+            page_addr += page_size;
+        }
+
+        let random_bytes = stack.top - 16u64;
+        self.write_user_bytes(random_bytes, &program.random_bytes)?;
+        let strings_len = program
+            .argv
+            .iter()
+            .chain(program.envp)
+            .map(|string| string.len() as u64 + 1)
+            .sum::<u64>();
+        let strings = random_bytes
+            .as_u64()
+            .checked_sub(strings_len)
+            .filter(|strings| *strings >= bottom.as_u64())
+            .ok_or(LoadUserProgramError::StackTooSmall)?;
+        let mut addr = VirtAddr::new(strings);
+        for string in program.argv.iter().chain(program.envp) {
+            self.write_user_bytes(addr, string)?;
+            self.write_user_bytes(addr + string.len() as u64, &[0])?;
+            addr += string.len() as u64 + 1;
+        }
+        Ok(StackStrings {
+            bottom,
+            strings: VirtAddr::new(strings),
+            random_bytes,
+        })
+    }
+
+    /// Writes `argc`, the `argv` and `envp` pointers, and the auxiliary vector below the strings, and returns the stack pointer
+    fn write_stack_vectors(
+        &mut self,
+        program: &UserProgram,
+        stack: StackStrings,
+        auxv: &[(u64, u64)],
+    ) -> Result<VirtAddr, LoadUserProgramError> {
+        let word_count =
+            (1 + program.argv.len() + 1 + program.envp.len() + 1) as u64 + 2 * auxv.len() as u64;
+        // The stack pointer must be 16 byte aligned when the program starts
+        let stack_pointer = stack
+            .strings
+            .as_u64()
+            .checked_sub(word_count * 8)
+            .map(|stack_pointer| stack_pointer & !0xf)
+            .filter(|stack_pointer| *stack_pointer >= stack.bottom.as_u64())
+            .ok_or(LoadUserProgramError::StackTooSmall)?;
+        let stack_pointer = VirtAddr::new(stack_pointer);
+        let mut addr = stack_pointer;
+        let mut push = |page_table: &mut Self, word: u64| {
+            let result = page_table.write_user_bytes(addr, &word.to_le_bytes());
+            addr += 8u64;
+            result
+        };
+        push(self, program.argv.len() as u64)?;
+        let mut string_addr = stack.strings.as_u64();
+        for strings in [program.argv, program.envp] {
+            for string in strings {
+                push(self, string_addr)?;
+                string_addr += string.len() as u64 + 1;
+            }
+            push(self, 0)?;
+        }
+        for (key, value) in auxv {
+            push(self, *key)?;
+            push(self, *value)?;
+        }
+        Ok(stack_pointer)
+    }
+
+    /// Writes to mapped memory in this page table, through the direct map
+    fn write_user_bytes(
+        &mut self,
+        mut addr: VirtAddr,
+        mut bytes: &[u8],
+    ) -> Result<(), LoadUserProgramError> {
+        while !bytes.is_empty() {
+            let frame = self
+                .leaf_entry_mut(addr)
+                .ok()
+                .and_then(|entry| entry.frame())
+                .expect("the stack was just mapped");
+            let phys_addr = frame.start_addr() + addr.as_u64() % frame.size().byte_len_u64();
+            let offset = (phys_addr.as_u64() % PageSize::_4KiB.byte_len_u64()) as usize;
+            let len = bytes.len().min(PageSize::_4KiB.byte_len() - offset);
+            unsafe {
+                self.config.with_4kib_frame(
+                    PhysFrame::containing_address(phys_addr),
+                    |frame_bytes| {
+                        frame_bytes[offset..][..len].copy_from_slice(&bytes[..len]);
+                    },
+                )
+            }
+            .ok_or(LoadUserProgramError::NotDirectlyMapped)?;
+            bytes = &bytes[len..];
+            addr += len as u64;
+        }
+        Ok(())
+    }
+}
+
+/// Addresses in a stack made by [`ManagedL4PageTable::create_user_stack`]
+struct StackStrings {
+    bottom: VirtAddr,
+    strings: VirtAddr,
+    random_bytes: VirtAddr,
+}
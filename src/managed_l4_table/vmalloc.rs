@@ -0,0 +1,176 @@
+use core::ops::Range;
+
+use x86_64::{
+    VirtAddr,
+    instructions::tlb::flush,
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+};
+
+use crate::*;
+
+use super::ManagedL4PageTable;
+
+#[derive(Debug)]
+pub enum VmallocError {
+    /// The [`KernelVirtualAllocator`] has no free range that is big enough
+    NoVirtualSpace,
+    /// The size is 0 or too big
+    InvalidSize,
+    FrameAllocationFailed,
+    Map(MapPageError),
+}
+
+impl ManagedL4PageTable {
+    /// Allocates `len` bytes (rounded up to 4 KiB) of virtual memory from `virtual_allocator`, and maps every page to a new 4 KiB frame.
+    /// The frames do not have to be contiguous in physical memory. They are not zeroed.
+    /// Fails with [`VmallocError::InvalidSize`] if `len` is 0 or too big.
+    /// If anything fails, everything that was allocated is freed.
+    ///
+    /// This must be a kernel page table, since the virtual memory is in the higher half.
+    ///
+    /// # Safety
+    /// Same as [`ManagedL4PageTable::map_page`]
+    pub unsafe fn vmalloc<const N: usize>(
+        &mut self,
+        virtual_allocator: &KernelVirtualAllocator<N>,
+        len: u64,
+        flags: ConfigurableFlags,
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    ) -> Result<Range<VirtAddr>, VmallocError> {
+        if len == 0
+            || len
+                .checked_next_multiple_of(PageSize::_4KiB.byte_len_u64())
+                .is_none()
+        {
+            return Err(VmallocError::InvalidSize);
+        }
+        let range = virtual_allocator
+            .allocate(len, PageSize::_4KiB.byte_len_u64())
+            .ok_or(VmallocError::NoVirtualSpace)?;
+        if let Err(e) = unsafe { self.map_new_frames(range.clone(), flags, frame_allocator) } {
+            virtual_allocator.free(range);
+            return Err(e);
+        }
+        Ok(range)
+    }
+
+    /// Maps every 4 KiB page in `range` to a new frame, and unmaps them again if anything fails
+    pub(super) unsafe fn map_new_frames(
+        &mut self,
+        range: Range<VirtAddr>,
+        flags: ConfigurableFlags,
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    ) -> Result<(), VmallocError> {
+        let mut addr = range.start;
+        while addr < range.end {
+            let result = match frame_allocator.allocate_frame() {
+                Some(frame) => unsafe {
+                    self.map_page(
+                        Page::new(addr, PageSize::_4KiB).unwrap(),
+                        Frame::new(frame.start_address(), PageSize::_4KiB).unwrap(),
+                        flags,
+                        frame_allocator,
+                    )
+                }
+                .map_err(|e| {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                    VmallocError::Map(e)
+                }),
+                None => Err(VmallocError::FrameAllocationFailed),
+            };
+            if let Err(e) = result {
+                // The pages that were mapped so far cannot fail to be unmapped
+                let _ = unsafe { self.vfree_pages(range.start..addr, frame_allocator) };
+                return Err(e);
+            }
+            addr += PageSize::_4KiB.byte_len_u64();
+        }
+        Ok(())
+    }
+
+    /// Frees memory allocated with [`ManagedL4PageTable::vmalloc`].
+    /// The pages are unmapped (with `invlpg`), and their frames and any page tables that become empty are given to `frame_deallocator`.
+    /// If a [`FrameDatabase`] is attached, frames are freed with [`FrameDatabase::release`], so pinned frames are freed when they are unpinned instead.
+    /// L3 tables are never freed, since they are shared with user page tables, and neither are tables from a [`StaticTablePool`].
+    ///
+    /// # Safety
+    /// `range` must have been returned by [`ManagedL4PageTable::vmalloc`] with the same `virtual_allocator`, and must not be used anymore.
+    /// Other CPUs could still have the pages in their TLBs until they flush them.
+    pub unsafe fn vfree<const N: usize>(
+        &mut self,
+        virtual_allocator: &KernelVirtualAllocator<N>,
+        range: Range<VirtAddr>,
+        frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) -> Result<(), UnmapPageError> {
+        unsafe { self.vfree_pages(range.clone(), frame_deallocator) }?;
+        virtual_allocator.free(range);
+        Ok(())
+    }
+
+    /// Unmaps every 4 KiB page in `range` and frees its frame and the page tables that become empty
+    pub(crate) unsafe fn vfree_pages(
+        &mut self,
+        range: Range<VirtAddr>,
+        frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) -> Result<(), UnmapPageError> {
+        let mut addr = range.start;
+        while addr < range.end {
+            let frame = unsafe { self.unmap_page(Page::new(addr, PageSize::_4KiB).unwrap()) }?;
+            self.config.release_frame(frame, |frame| unsafe {
+                frame_deallocator
+                    .deallocate_frame(PhysFrame::from_start_address(frame.start_addr()).unwrap())
+            });
+            addr += PageSize::_4KiB.byte_len_u64();
+        }
+        self.free_empty_tables(range, frame_deallocator);
+        Ok(())
+    }
+
+    /// Frees the L1 and L2 tables covering `range` that have no entries
+    pub(super) fn free_empty_tables(
+        &mut self,
+        range: Range<VirtAddr>,
+        frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) {
+        // L1 tables first, so that the L2 tables containing them can become empty
+        for size in [PageSize::_2MiB, PageSize::_1GiB] {
+            // As u64, since the address after the last table can be non-canonical or overflow
+            let mut addr = Some(range.start.align_down(size.byte_len_u64()).as_u64());
+            while let Some(table_addr) = addr.filter(|addr| *addr < range.end.as_u64()) {
+                let page = Page::new(VirtAddr::new(table_addr), size).unwrap();
+                self.free_table_if_empty(page, frame_deallocator);
+                addr = table_addr.checked_add(size.byte_len_u64());
+            }
+        }
+    }
+
+    /// Frees the table that the entry mapping `page` points to, if the table has no entries
+    fn free_table_if_empty(
+        &mut self,
+        page: Page,
+        frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) {
+        let Ok(entry) = self.page_entry_mut(page) else {
+            return;
+        };
+        if entry.is_empty() || entry.frame().is_some() {
+            return;
+        }
+        let table_frame = entry.entry.frame(false).unwrap();
+        let Ok(table) = entry.get_page_table_mut() else {
+            return;
+        };
+        let is_empty = unsafe { table.page_table.as_ref() }
+            .iter()
+            .all(|entry| entry.is_unused());
+        drop(table);
+        if !is_empty || self.is_static_table(table_frame) {
+            return;
+        }
+        self.page_entry_mut(page).unwrap().entry.set_unused();
+        // Also flushes the paging-structure caches for the page
+        flush(page.start_addr());
+        self.config.mark_page_table(table_frame, false);
+        unsafe { frame_deallocator.deallocate_frame(table_frame) };
+    }
+}
@@ -41,4 +41,24 @@ impl ManagedPat {
         }
         Some(flags)
     }
+
+    /// Get the caching memory type selected by the page table bits of a page.
+    /// This is the reverse of [`ManagedPat::get_page_table_flags`].
+    pub fn get_memory_type(&self, flags: PageTableFlags, page_size: PageSize) -> PatMemoryType {
+        let mut pat_msr_index = 0;
+        if flags.contains(PageTableFlags::WRITE_THROUGH) {
+            pat_msr_index |= 0b001;
+        }
+        if flags.contains(PageTableFlags::NO_CACHE) {
+            pat_msr_index |= 0b010;
+        }
+        let pat_flag = match page_size {
+            PageSize::_1GiB | PageSize::_2MiB => PageTableFlags::PAT_HUGE_PAGE,
+            PageSize::_4KiB => PageTableFlags::PAT_4KIB_PAGE,
+        };
+        if flags.contains(pat_flag) {
+            pat_msr_index |= 0b100;
+        }
+        Pat::read()[pat_msr_index]
+    }
 }
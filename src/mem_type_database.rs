@@ -0,0 +1,227 @@
+use core::{fmt::Debug, ops::Range};
+
+use x86_64::{PhysAddr, registers::model_specific::PatMemoryType};
+
+use crate::*;
+
+/// A physical range that is mapped with a specific memory type by `count` mappings
+#[derive(Debug, Clone)]
+pub struct MemTypeReservation {
+    pub range: Range<PhysAddr>,
+    pub memory_type: PatMemoryType,
+    pub count: usize,
+}
+
+#[derive(Debug)]
+pub enum ReserveMemTypeError {
+    /// Part of the range is already reserved with a different memory type
+    Conflict(PatMemoryType),
+    /// There is no space left in the database
+    Full,
+}
+
+/// What to do when mapping a frame with a memory type that is different from the frame's alias in the direct map
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemTypeConflictPolicy {
+    /// Fail to map the page
+    Reject,
+    /// Split the direct map's huge page covering the frame as needed and change the memory type of the frame in the direct map.
+    /// The direct map gets changed back to write-back when the last reservation of the frame is freed.
+    RetypeDirectMap,
+}
+
+/// Keeps track of which memory types physical ranges are mapped with, so that no physical memory is mapped with conflicting memory types.
+/// This is similar to Linux's memtype tree.
+///
+/// Reservations are kept in a fixed size array, so that the database can be put in a `static`.
+pub struct MemTypeDatabase<const N: usize> {
+    reservations: SpinLock<[Option<MemTypeReservation>; N]>,
+}
+
+impl<const N: usize> MemTypeDatabase<N> {
+    pub const fn new() -> Self {
+        Self {
+            reservations: SpinLock::new([const { None }; N]),
+        }
+    }
+
+    /// Reserves a physical range with a memory type.
+    /// Reserving the same range with the same memory type again increases the reservation's count.
+    ///
+    /// Write-back is the memory type of RAM that is not reserved, so write-back reservations are only checked for conflicts and do not use space in the database.
+    pub fn reserve(
+        &self,
+        range: Range<PhysAddr>,
+        memory_type: PatMemoryType,
+    ) -> Result<(), ReserveMemTypeError> {
+        self.retype(range, PatMemoryType::WriteBack, memory_type, 1)
+    }
+
+    /// Moves `count` reservations of a range from one memory type to another, such as when the memory type of the pages mapping it changes.
+    /// This is done at once, so that the old reservations do not conflict with the new ones, and nothing else can reserve the range in between.
+    /// Fails if any other reservation overlapping the range has a different memory type than `to`, including reservations of the range with `from` that are not moved.
+    pub fn retype(
+        &self,
+        range: Range<PhysAddr>,
+        from: PatMemoryType,
+        to: PatMemoryType,
+        count: usize,
+    ) -> Result<(), ReserveMemTypeError> {
+        let mut reservations = self.reservations.lock();
+        let from_index = reservations.iter().position(|reservation| {
+            reservation.as_ref().is_some_and(|reservation| {
+                reservation.range == range
+                    && reservation.memory_type == from
+                    && from != PatMemoryType::WriteBack
+            })
+        });
+        // If every reservation with the old memory type is moved, its slot is not a conflict anymore and can be reused
+        let freed_index = from_index.filter(|index| {
+            reservations[*index]
+                .as_ref()
+                .is_some_and(|reservation| reservation.count <= count)
+        });
+        if let Some(conflict) = reservations
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| Some(*index) != freed_index)
+            .filter_map(|(_, reservation)| reservation.as_ref())
+            .find(|reservation| {
+                overlaps(&reservation.range, &range) && reservation.memory_type != to
+            })
+        {
+            return Err(ReserveMemTypeError::Conflict(conflict.memory_type));
+        }
+        let to_index = match to {
+            PatMemoryType::WriteBack => None,
+            _ => Some(
+                reservations
+                    .iter()
+                    .position(|reservation| {
+                        reservation.as_ref().is_some_and(|reservation| {
+                            reservation.range == range && reservation.memory_type == to
+                        })
+                    })
+                    .or(freed_index)
+                    .or_else(|| {
+                        reservations
+                            .iter()
+                            .position(|reservation| reservation.is_none())
+                    })
+                    .ok_or(ReserveMemTypeError::Full)?,
+            ),
+        };
+        if let Some(index) = from_index {
+            let reservation = reservations[index].as_mut().unwrap();
+            reservation.count = reservation.count.saturating_sub(count);
+            if reservation.count == 0 {
+                reservations[index] = None;
+            }
+        }
+        if let Some(index) = to_index {
+            match &mut reservations[index] {
+                Some(reservation) => reservation.count += count,
+                slot => {
+                    *slot = Some(MemTypeReservation {
+                        range,
+                        memory_type: to,
+                        count,
+                    })
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Frees a reservation made with [`MemTypeDatabase::reserve`].
+    /// Returns `false` if there was no matching reservation.
+    /// Freeing a write-back reservation does nothing.
+    pub fn free(&self, range: Range<PhysAddr>, memory_type: PatMemoryType) -> bool {
+        if memory_type == PatMemoryType::WriteBack {
+            return true;
+        }
+        let mut reservations = self.reservations.lock();
+        let Some(slot) = reservations.iter_mut().find(|reservation| {
+            reservation.as_ref().is_some_and(|reservation| {
+                reservation.range == range && reservation.memory_type == memory_type
+            })
+        }) else {
+            return false;
+        };
+        let reservation = slot.as_mut().unwrap();
+        reservation.count -= 1;
+        if reservation.count == 0 {
+            *slot = None;
+        }
+        true
+    }
+
+    /// Returns the memory type that any part of the range is reserved with
+    pub fn lookup(&self, range: Range<PhysAddr>) -> Option<PatMemoryType> {
+        self.reservations
+            .lock()
+            .iter()
+            .flatten()
+            .find(|reservation| overlaps(&reservation.range, &range))
+            .map(|reservation| reservation.memory_type)
+    }
+}
+
+impl<const N: usize> Default for MemTypeDatabase<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Debug for MemTypeDatabase<N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MemTypeDatabase")
+            .field("capacity", &N)
+            .finish_non_exhaustive()
+    }
+}
+
+fn overlaps(a: &Range<PhysAddr>, b: &Range<PhysAddr>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// Lets [`PagingConfig`] hold a [`MemTypeDatabase`] of any size
+pub(crate) trait MemTypeTracker: Debug + Sync {
+    fn retype(
+        &self,
+        range: Range<PhysAddr>,
+        from: PatMemoryType,
+        to: PatMemoryType,
+        count: usize,
+    ) -> Result<(), ReserveMemTypeError>;
+
+    fn free(&self, range: Range<PhysAddr>, memory_type: PatMemoryType) -> bool;
+
+    fn lookup(&self, range: Range<PhysAddr>) -> Option<PatMemoryType>;
+}
+
+impl<const N: usize> MemTypeTracker for MemTypeDatabase<N> {
+    fn retype(
+        &self,
+        range: Range<PhysAddr>,
+        from: PatMemoryType,
+        to: PatMemoryType,
+        count: usize,
+    ) -> Result<(), ReserveMemTypeError> {
+        MemTypeDatabase::retype(self, range, from, to, count)
+    }
+
+    fn free(&self, range: Range<PhysAddr>, memory_type: PatMemoryType) -> bool {
+        MemTypeDatabase::free(self, range, memory_type)
+    }
+
+    fn lookup(&self, range: Range<PhysAddr>) -> Option<PatMemoryType> {
+        MemTypeDatabase::lookup(self, range)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct AttachedMemTypeDatabase {
+    pub database: &'static dyn MemTypeTracker,
+    pub policy: MemTypeConflictPolicy,
+}
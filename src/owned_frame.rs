@@ -0,0 +1,37 @@
+use core::ops::Deref;
+
+use crate::*;
+
+/// A [`Frame`] of any [`PageSize`] that is "owned" by whatever owns it.
+/// Map it with [`ManagedL4PageTable::map_owned_page`] to give ownership to the page table, and get it back with [`ManagedL4PageTable::unmap_owned_page`].
+#[derive(Debug)]
+pub struct OwnedFrame(pub(crate) Frame);
+
+impl OwnedFrame {
+    /// # Safety
+    /// - The frame must be valid physical memory
+    /// - The memory cannot be used or referenced by anything else
+    pub unsafe fn new(frame: Frame) -> Self {
+        Self(frame)
+    }
+}
+
+impl Deref for OwnedFrame {
+    type Target = Frame;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<OwnedFrame> for Frame {
+    fn from(value: OwnedFrame) -> Self {
+        value.0
+    }
+}
+
+impl From<Owned4KibFrame> for OwnedFrame {
+    fn from(value: Owned4KibFrame) -> Self {
+        Self(Frame::new(value.0.start_address(), PageSize::_4KiB).unwrap())
+    }
+}
@@ -1,13 +1,73 @@
+use x86_64::structures::paging::PageTableIndex;
+
 use crate::*;
 
 #[derive(Debug, Clone, Copy)]
 pub struct PagingConfig {
-    pub(crate) offset: VirtualOffset,
+    pub(crate) table_access: TableAccess,
     pub(crate) pat: ManagedPat,
+    pub(crate) mem_types: Option<AttachedMemTypeDatabase>,
+    pub(crate) frame_database: Option<&'static FrameDatabase>,
+    pub(crate) reverse_map: Option<&'static dyn MappingTracker>,
 }
 
 impl PagingConfig {
     pub fn new(pat: ManagedPat, offset: VirtualOffset) -> Self {
-        Self { pat, offset }
+        Self {
+            pat,
+            table_access: TableAccess::DirectMap(offset),
+            mem_types: None,
+            frame_database: None,
+            reverse_map: None,
+        }
+    }
+
+    /// Access page tables through a recursive L4 entry instead of a direct map, similar to x86_64's `RecursivePageTable`.
+    /// Every L4 table points to itself at `recursive_index`, which [`PagingConfig::new_kernel`] and [`ManagedL4PageTable::new_user`] set up automatically.
+    /// To access a L4 table that is not the active one, the active L4 table's entry at `window_index` is temporarily pointed to it.
+    ///
+    /// Both indexes are in the higher half, and cannot be used for mappings.
+    /// Since there is no direct map, features that need the direct map, such as [`PagingConfig::phys_ptr`] and [`ManagedL4PageTable::split_page`], are not available.
+    ///
+    /// # Safety
+    /// - The active L4 table must always point to itself at `recursive_index`, including the one that is active when calling this
+    /// - The active L4 table's entry at `window_index` must not be used by anything else
+    /// - Page tables must only be accessed by one CPU at a time, and one page table must be done being accessed before accessing another
+    pub unsafe fn new_recursive(
+        pat: ManagedPat,
+        recursive_index: PageTableIndex,
+        window_index: PageTableIndex,
+    ) -> Self {
+        assert!(
+            u16::from(recursive_index) >= 256
+                && u16::from(window_index) >= 256
+                && recursive_index != window_index,
+            "The recursive and window indexes must be different entries in the higher half"
+        );
+        Self {
+            pat,
+            table_access: TableAccess::Recursive(RecursiveIndexes {
+                recursive: recursive_index,
+                window: window_index,
+            }),
+            mem_types: None,
+            frame_database: None,
+            reverse_map: None,
+        }
+    }
+
+    /// Makes [`ManagedL4PageTable::map_page`] reserve the memory type of every frame it maps in `database`,
+    /// and fail if the frame is already mapped with a different memory type.
+    /// [`ManagedL4PageTable::unmap_page`] frees the reservation.
+    ///
+    /// Mappings made with the kernel page table are also checked against the direct map, and `policy` decides what happens if the memory types conflict.
+    /// Pages inside the direct map itself are not reserved.
+    pub fn with_mem_type_database<const N: usize>(
+        mut self,
+        database: &'static MemTypeDatabase<N>,
+        policy: MemTypeConflictPolicy,
+    ) -> Self {
+        self.mem_types = Some(AttachedMemTypeDatabase { database, policy });
+        self
     }
 }
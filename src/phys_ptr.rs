@@ -0,0 +1,241 @@
+use core::{mem::align_of, ptr::NonNull};
+
+use x86_64::{PhysAddr, VirtAddr, structures::paging::PhysFrame};
+
+use crate::*;
+
+/// A pointer to a `T` in physical memory, which is accessed through the direct map
+#[derive(Debug)]
+pub struct PhysPtr<T> {
+    phys_addr: PhysAddr,
+    ptr: NonNull<T>,
+}
+
+impl<T> Clone for PhysPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for PhysPtr<T> {}
+
+impl<T> PhysPtr<T> {
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys_addr
+    }
+
+    /// The pointer in the direct map
+    pub fn as_ptr(&self) -> *mut T {
+        self.ptr.as_ptr()
+    }
+
+    /// # Safety
+    /// Same as [`core::ptr::read`]
+    pub unsafe fn read(&self) -> T {
+        unsafe { self.ptr.read() }
+    }
+
+    /// # Safety
+    /// Same as [`core::ptr::write`]
+    pub unsafe fn write(&self, value: T) {
+        unsafe { self.ptr.write(value) }
+    }
+
+    /// Use this for MMIO registers.
+    ///
+    /// # Safety
+    /// Same as [`core::ptr::read_volatile`]
+    pub unsafe fn read_volatile(&self) -> T {
+        unsafe { self.ptr.read_volatile() }
+    }
+
+    /// Use this for MMIO registers.
+    ///
+    /// # Safety
+    /// Same as [`core::ptr::write_volatile`]
+    pub unsafe fn write_volatile(&self, value: T) {
+        unsafe { self.ptr.write_volatile(value) }
+    }
+}
+
+/// `len` consecutive `T`s in physical memory, which are accessed through the direct map
+#[derive(Debug)]
+pub struct PhysSlice<T> {
+    start: PhysPtr<T>,
+    len: usize,
+}
+
+impl<T> Clone for PhysSlice<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for PhysSlice<T> {}
+
+impl<T> PhysSlice<T> {
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.start.phys_addr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<PhysPtr<T>> {
+        if index < self.len {
+            Some(PhysPtr {
+                phys_addr: self.start.phys_addr + (index * size_of::<T>()) as u64,
+                ptr: unsafe { self.start.ptr.add(index) },
+            })
+        } else {
+            None
+        }
+    }
+
+    /// # Safety
+    /// The memory must contain valid `T`s and must not be modified while the slice is borrowed.
+    pub unsafe fn as_slice(&self) -> &[T] {
+        unsafe { NonNull::slice_from_raw_parts(self.start.ptr, self.len).as_ref() }
+    }
+
+    /// # Safety
+    /// The memory must contain valid `T`s and must not be accessed in any other way while the slice is borrowed.
+    pub unsafe fn as_slice_mut(&mut self) -> &mut [T] {
+        unsafe { NonNull::slice_from_raw_parts(self.start.ptr, self.len).as_mut() }
+    }
+
+    /// Copies `src` into the start of this slice.
+    /// Panics if `src` is longer than this slice.
+    ///
+    /// # Safety
+    /// The memory must not be accessed in any other way while it is being written to.
+    pub unsafe fn copy_from_slice(&self, src: &[T])
+    where
+        T: Copy,
+    {
+        assert!(src.len() <= self.len);
+        unsafe {
+            self.start
+                .ptr
+                .copy_from_nonoverlapping(NonNull::from(src).cast(), src.len())
+        };
+    }
+}
+
+#[derive(Debug)]
+pub enum CopyFrameError {
+    /// The frames have different sizes
+    SizeMismatch,
+    NotDirectlyMapped,
+}
+
+impl PagingConfig {
+    /// Returns `None` if `phys_addr` is not aligned for `T` or the `T` is not in the direct map
+    pub fn phys_ptr<T>(&self, phys_addr: PhysAddr) -> Option<PhysPtr<T>> {
+        self.phys_slice(phys_addr, 1).map(|slice| slice.start)
+    }
+
+    /// Returns `None` if `phys_addr` is not aligned for `T` or the slice is not in the direct map
+    pub fn phys_slice<T>(&self, phys_addr: PhysAddr, len: usize) -> Option<PhysSlice<T>> {
+        if !phys_addr.is_aligned(align_of::<T>() as u64) {
+            return None;
+        }
+        let byte_len = len.checked_mul(size_of::<T>())?;
+        let virt_addr = phys_range_to_virt(phys_addr, byte_len as u64, self)?;
+        Some(PhysSlice {
+            start: PhysPtr {
+                phys_addr,
+                ptr: NonNull::new(virt_addr.as_mut_ptr())?,
+            },
+            len,
+        })
+    }
+
+    /// Gets the physical address of a virtual address in the direct map.
+    /// Returns `None` if `virt_addr` is not in the direct map, or if there is no direct map.
+    /// A dense direct map ends at the CPU's maximum physical address.
+    pub fn to_phys(&self, virt_addr: VirtAddr) -> Option<PhysAddr> {
+        let offset = self.direct_map()?;
+        let phys_addr = PhysAddr::try_new(virt_addr.as_u64().checked_sub(**offset)?).ok()?;
+        offset.is_mapped(phys_addr, 1).then_some(phys_addr)
+    }
+
+    /// Fills a frame of any size with zeroes.
+    /// In kmap mode, the frame is mapped 4 KiB at a time.
+    ///
+    /// # Safety
+    /// The frame must not be in use by anything else.
+    pub unsafe fn zero_frame(&self, frame: Frame) -> Result<(), NotDirectlyMapped> {
+        if let Some(slice) = self.phys_slice::<u8>(frame.start_addr(), frame.size().byte_len()) {
+            unsafe { slice.start.ptr.write_bytes(0, slice.len) };
+            return Ok(());
+        }
+        let window = self.kmap_window().ok_or(NotDirectlyMapped)?;
+        for chunk in frame_chunks(frame) {
+            let guard = window.map(chunk);
+            unsafe { guard.as_mut_ptr::<u8>().write_bytes(0, CHUNK_LEN) };
+        }
+        Ok(())
+    }
+
+    /// Copies the contents of `src` to `dst`.
+    ///
+    /// # Safety
+    /// `dst` must not be in use by anything else, and `src` must not be written to while it is being copied.
+    pub unsafe fn copy_frame(&self, src: Frame, dst: Frame) -> Result<(), CopyFrameError> {
+        if src.size() != dst.size() {
+            return Err(CopyFrameError::SizeMismatch);
+        }
+        let len = src.size().byte_len();
+        if let (Some(src), Some(dst)) = (
+            self.phys_slice::<u8>(src.start_addr(), len),
+            self.phys_slice::<u8>(dst.start_addr(), len),
+        ) {
+            unsafe { dst.start.ptr.copy_from_nonoverlapping(src.start.ptr, len) };
+            return Ok(());
+        }
+        let window = self
+            .kmap_window()
+            .ok_or(CopyFrameError::NotDirectlyMapped)?;
+        for (src, dst) in frame_chunks(src).zip(frame_chunks(dst)) {
+            let src = window.map(src);
+            let dst = window.map(dst);
+            unsafe {
+                dst.as_mut_ptr::<u8>()
+                    .copy_from_nonoverlapping(src.as_mut_ptr::<u8>(), CHUNK_LEN)
+            };
+        }
+        Ok(())
+    }
+
+    /// Accesses a 4 KiB frame through the direct map, or by mapping it in the kmap window
+    ///
+    /// # Safety
+    /// The frame must not be accessed in any other way during `f`
+    #[cfg(feature = "elf")]
+    pub(crate) unsafe fn with_4kib_frame<R>(
+        &self,
+        frame: PhysFrame,
+        f: impl FnOnce(&mut [u8; CHUNK_LEN]) -> R,
+    ) -> Option<R> {
+        if let Some(mut slice) = self.phys_slice::<[u8; CHUNK_LEN]>(frame.start_address(), 1) {
+            return Some(f(unsafe { &mut slice.as_slice_mut()[0] }));
+        }
+        let guard = self.kmap_window()?.map(frame);
+        Some(f(unsafe { &mut *guard.as_mut_ptr() }))
+    }
+}
+
+const CHUNK_LEN: usize = PageSize::_4KiB.byte_len();
+
+/// The 4 KiB frames that make up a frame
+fn frame_chunks(frame: Frame) -> impl Iterator<Item = PhysFrame> {
+    (0..frame.size().byte_len_u64())
+        .step_by(CHUNK_LEN)
+        .map(move |offset| PhysFrame::from_start_address(frame.start_addr() + offset).unwrap())
+}
@@ -0,0 +1,226 @@
+use core::fmt::Debug;
+
+use x86_64::{PhysAddr, structures::paging::PhysFrame};
+
+use crate::*;
+
+/// A page that maps a frame, and the L4 page table that it is mapped in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub l4_frame: PhysFrame,
+    pub page: Page,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ReverseMapEntry {
+    frame_start: PhysAddr,
+    frame_size: PageSize,
+    mapping: Mapping,
+}
+
+impl ReverseMapEntry {
+    fn maps(&self, frame: Frame) -> bool {
+        self.frame_start == frame.start_addr() && self.frame_size == frame.size()
+    }
+}
+
+#[derive(Debug)]
+pub enum RecordMappingError {
+    /// There is no space left in the reverse map
+    Full,
+}
+
+/// Keeps track of which pages map each frame, so that all mappings of a frame can be found, such as when migrating or offlining it.
+/// This is similar to Linux's rmap.
+///
+/// Mappings are kept in a fixed size array, so that the reverse map can be put in a `static`.
+pub struct ReverseMap<const N: usize> {
+    entries: SpinLock<[Option<ReverseMapEntry>; N]>,
+}
+
+impl<const N: usize> ReverseMap<N> {
+    pub const fn new() -> Self {
+        Self {
+            entries: SpinLock::new([const { None }; N]),
+        }
+    }
+
+    pub fn record(&self, frame: Frame, mapping: Mapping) -> Result<(), RecordMappingError> {
+        let mut entries = self.entries.lock();
+        let slot = entries
+            .iter_mut()
+            .find(|entry| entry.is_none())
+            .ok_or(RecordMappingError::Full)?;
+        *slot = Some(ReverseMapEntry {
+            frame_start: frame.start_addr(),
+            frame_size: frame.size(),
+            mapping,
+        });
+        Ok(())
+    }
+
+    /// Removes a mapping recorded with [`ReverseMap::record`].
+    /// Returns `false` if there was no matching mapping.
+    pub fn remove(&self, frame: Frame, mapping: Mapping) -> bool {
+        let mut entries = self.entries.lock();
+        let Some(slot) = entries.iter_mut().find(|entry| {
+            entry
+                .as_ref()
+                .is_some_and(|entry| entry.maps(frame) && entry.mapping == mapping)
+        }) else {
+            return false;
+        };
+        *slot = None;
+        true
+    }
+
+    /// Changes the frame of a mapping recorded with [`ReverseMap::record`] from `old` to `new`, such as when the page is moved to another frame.
+    /// This is done at once, so it does not need a free slot.
+    /// Returns `false` if there was no matching mapping.
+    pub fn move_mapping(&self, old: Frame, new: Frame, mapping: Mapping) -> bool {
+        let mut entries = self.entries.lock();
+        let Some(entry) = entries
+            .iter_mut()
+            .flatten()
+            .find(|entry| entry.maps(old) && entry.mapping == mapping)
+        else {
+            return false;
+        };
+        entry.frame_start = new.start_addr();
+        entry.frame_size = new.size();
+        true
+    }
+
+    /// Calls `f` with every mapping of `frame`.
+    /// Use [`ManagedL4PageTable::from_mapping`] to access the page table that a mapping is in.
+    /// The lock is not held while calling `f`, so `f` can map and unmap pages.
+    pub fn for_each_mapping(&self, frame: Frame, mut f: impl FnMut(Mapping)) {
+        for i in 0..N {
+            let entry = self.entries.lock()[i];
+            if let Some(entry) = entry.filter(|entry| entry.maps(frame)) {
+                f(entry.mapping);
+            }
+        }
+    }
+}
+
+impl<const N: usize> Default for ReverseMap<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Debug for ReverseMap<N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ReverseMap")
+            .field("capacity", &N)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Lets [`PagingConfig`] hold a [`ReverseMap`] of any size
+pub(crate) trait MappingTracker: Debug + Sync {
+    fn record(&self, frame: Frame, mapping: Mapping) -> Result<(), RecordMappingError>;
+
+    fn remove(&self, frame: Frame, mapping: Mapping) -> bool;
+
+    fn move_mapping(&self, old: Frame, new: Frame, mapping: Mapping) -> bool;
+
+    fn first_mapping(&self, frame: Frame) -> Option<Mapping>;
+
+    fn for_each_mapping(&self, frame: Frame, f: &mut dyn FnMut(Mapping));
+}
+
+impl<const N: usize> MappingTracker for ReverseMap<N> {
+    fn record(&self, frame: Frame, mapping: Mapping) -> Result<(), RecordMappingError> {
+        ReverseMap::record(self, frame, mapping)
+    }
+
+    fn remove(&self, frame: Frame, mapping: Mapping) -> bool {
+        ReverseMap::remove(self, frame, mapping)
+    }
+
+    fn move_mapping(&self, old: Frame, new: Frame, mapping: Mapping) -> bool {
+        ReverseMap::move_mapping(self, old, new, mapping)
+    }
+
+    fn first_mapping(&self, frame: Frame) -> Option<Mapping> {
+        self.entries
+            .lock()
+            .iter()
+            .flatten()
+            .find(|entry| entry.maps(frame))
+            .map(|entry| entry.mapping)
+    }
+
+    fn for_each_mapping(&self, frame: Frame, f: &mut dyn FnMut(Mapping)) {
+        ReverseMap::for_each_mapping(self, frame, f)
+    }
+}
+
+const BATCH_LEN: usize = 32;
+
+/// Pages that were unmapped and still need to be flushed from the TLBs of other CPUs.
+/// The CPU that unmapped them already flushed them.
+/// If too many pages were unmapped to remember all of them, other CPUs should flush their whole TLB.
+#[derive(Debug, Clone)]
+pub struct TlbShootdownBatch {
+    mappings: [Option<Mapping>; BATCH_LEN],
+    len: usize,
+    overflowed: bool,
+}
+
+impl TlbShootdownBatch {
+    pub const fn new() -> Self {
+        Self {
+            mappings: [None; BATCH_LEN],
+            len: 0,
+            overflowed: false,
+        }
+    }
+
+    pub fn push(&mut self, mapping: Mapping) {
+        match self.mappings.get_mut(self.len) {
+            Some(slot) => {
+                *slot = Some(mapping);
+                self.len += 1;
+            }
+            None => self.overflowed = true,
+        }
+    }
+
+    /// Adds the pages of another batch to this one
+    pub fn append(&mut self, other: &Self) {
+        other.mappings().for_each(|mapping| self.push(*mapping));
+        self.overflowed |= other.overflowed;
+    }
+
+    /// The pages to flush on other CPUs which have the L4 table active (or any CPU, for pages in the higher half)
+    pub fn mappings(&self) -> impl Iterator<Item = &Mapping> {
+        self.mappings.iter().flatten()
+    }
+
+    /// If `true`, other CPUs should flush their whole TLB instead of the pages in [`TlbShootdownBatch::mappings`]
+    pub fn needs_full_flush(&self) -> bool {
+        self.overflowed
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0 && !self.overflowed
+    }
+}
+
+impl Default for TlbShootdownBatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PagingConfig {
+    /// Makes [`ManagedL4PageTable::map_page`] record every page it maps in `reverse_map`, and fail if the reverse map is full.
+    /// [`ManagedL4PageTable::unmap_page`] removes the mapping.
+    pub fn with_reverse_map<const N: usize>(mut self, reverse_map: &'static ReverseMap<N>) -> Self {
+        self.reverse_map = Some(reverse_map);
+        self
+    }
+}
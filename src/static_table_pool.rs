@@ -0,0 +1,89 @@
+use core::{
+    cell::UnsafeCell,
+    fmt::Debug,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use x86_64::{
+    PhysAddr,
+    structures::paging::{FrameAllocator, PageTable, PhysFrame, Size4KiB, frame::PhysFrameRange},
+};
+
+/// `N` page tables in a `static` (which ends up in `.bss`), for creating page tables before there is a frame allocator.
+/// Frames from the pool are never freed.
+#[repr(C, align(4096))]
+pub struct StaticTablePool<const N: usize> {
+    // This must be the first field, so that it is at the physical address of the struct
+    tables: [UnsafeCell<PageTable>; N],
+    phys_addr: AtomicU64,
+    next: AtomicUsize,
+}
+
+unsafe impl<const N: usize> Sync for StaticTablePool<N> {}
+
+const UNINITIALIZED: u64 = u64::MAX;
+
+impl<const N: usize> StaticTablePool<N> {
+    pub const fn new() -> Self {
+        Self {
+            tables: [const { UnsafeCell::new(PageTable::new()) }; N],
+            phys_addr: AtomicU64::new(UNINITIALIZED),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sets the physical address of the pool, which must be done before allocating from it.
+    ///
+    /// # Safety
+    /// `phys_addr` must be the physical address of `self`
+    pub unsafe fn init(&self, phys_addr: PhysAddr) {
+        assert!(phys_addr.is_aligned(0x1000u64));
+        self.phys_addr.store(phys_addr.as_u64(), Ordering::Release);
+    }
+
+    /// The frames of all tables in the pool, including ones that were not allocated yet.
+    /// Panics if the pool was not initialized.
+    pub fn frames(&self) -> PhysFrameRange {
+        let phys_addr = self.phys_addr.load(Ordering::Acquire);
+        assert_ne!(phys_addr, UNINITIALIZED, "The pool was not initialized");
+        let start = PhysFrame::containing_address(PhysAddr::new(phys_addr));
+        PhysFrame::range(start, start + N as u64)
+    }
+
+    /// The number of tables that can still be allocated
+    pub fn remaining(&self) -> usize {
+        N - self.next.load(Ordering::Relaxed).min(N)
+    }
+
+    /// Panics if the pool was not initialized
+    pub fn allocate(&self) -> Option<PhysFrame> {
+        let frames = self.frames();
+        let index = self.next.fetch_add(1, Ordering::Relaxed);
+        if index >= N {
+            self.next.store(N, Ordering::Relaxed);
+            return None;
+        }
+        Some(frames.start + index as u64)
+    }
+}
+
+impl<const N: usize> Default for StaticTablePool<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Debug for StaticTablePool<N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("StaticTablePool")
+            .field("capacity", &N)
+            .field("remaining", &self.remaining())
+            .finish_non_exhaustive()
+    }
+}
+
+unsafe impl<const N: usize> FrameAllocator<Size4KiB> for &StaticTablePool<N> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        self.allocate()
+    }
+}
@@ -0,0 +1,192 @@
+use core::ptr::NonNull;
+
+use x86_64::{
+    PhysAddr, VirtAddr,
+    instructions::tlb::flush_all,
+    registers::control::Cr3,
+    structures::paging::{
+        PageTable, PageTableFlags, PageTableIndex, PhysFrame, page_table::PageTableEntry,
+    },
+};
+
+use crate::*;
+
+/// How page tables are accessed
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum TableAccess {
+    /// Through the direct map
+    DirectMap(VirtualOffset),
+    /// Through recursive virtual addresses, like x86_64's `RecursivePageTable`
+    Recursive(RecursiveIndexes),
+    /// By temporarily mapping tables in a [`KmapWindow`]
+    Kmap {
+        window: &'static KmapWindow,
+        window_phys_addr: PhysAddr,
+    },
+}
+
+/// A pointer to a page table, which stays valid until this is dropped
+#[derive(Debug)]
+pub(crate) struct TablePtr {
+    pub ptr: NonNull<PageTable>,
+    /// In kmap mode, the table is unmapped when this is dropped
+    pub kmap_guard: Option<KmapGuard>,
+}
+
+impl TablePtr {
+    fn new(ptr: *mut PageTable) -> Option<Self> {
+        Some(Self {
+            ptr: NonNull::new(ptr)?,
+            kmap_guard: None,
+        })
+    }
+
+    fn kmap(window: &'static KmapWindow, frame: PhysFrame) -> Option<Self> {
+        let guard = window.map(frame);
+        Some(Self {
+            ptr: NonNull::new(guard.as_mut_ptr())?,
+            kmap_guard: Some(guard),
+        })
+    }
+}
+
+/// The L4 entries used for accessing page tables recursively
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RecursiveIndexes {
+    /// Every L4 table points to itself at this index
+    pub recursive: PageTableIndex,
+    /// The active L4 table points to the L4 table being accessed at this index, if it is not the active one
+    pub window: PageTableIndex,
+}
+
+fn recursive_addr(indexes: [PageTableIndex; 4]) -> VirtAddr {
+    let [l4, l3, l2, l1] = indexes.map(u64::from);
+    VirtAddr::new_truncate((l4 << 39) | (l3 << 30) | (l2 << 21) | (l1 << 12))
+}
+
+impl PagingConfig {
+    pub(crate) fn direct_map(&self) -> Option<&VirtualOffset> {
+        match &self.table_access {
+            TableAccess::DirectMap(offset) => Some(offset),
+            TableAccess::Recursive(_) | TableAccess::Kmap { .. } => None,
+        }
+    }
+
+    pub(crate) fn kmap_window(&self) -> Option<&'static KmapWindow> {
+        match self.table_access {
+            TableAccess::Kmap { window, .. } => Some(window),
+            TableAccess::DirectMap(_) | TableAccess::Recursive(_) => None,
+        }
+    }
+
+    /// L4 indexes which are used for accessing page tables, and cannot be used for mappings
+    pub(crate) fn reserved_l4_indexes(&self) -> Option<RecursiveIndexes> {
+        match self.table_access {
+            TableAccess::DirectMap(_) | TableAccess::Kmap { .. } => None,
+            TableAccess::Recursive(indexes) => Some(indexes),
+        }
+    }
+
+    /// Whether the L4 entry at `index` is used for accessing page tables, and cannot be used for mappings
+    pub(crate) fn is_reserved_l4_index(&self, index: PageTableIndex) -> bool {
+        match self.table_access {
+            TableAccess::DirectMap(_) => false,
+            TableAccess::Recursive(RecursiveIndexes { recursive, window }) => {
+                index == recursive || index == window
+            }
+            TableAccess::Kmap { window, .. } => index == window.base().p4_index(),
+        }
+    }
+
+    /// Gets a pointer to a L4 table.
+    /// In recursive mode, this points the window entry of the active L4 table to `frame`,
+    /// so the pointer is only valid until a different L4 table is accessed.
+    pub(crate) fn l4_table_ptr(&self, frame: PhysFrame) -> Option<TablePtr> {
+        match self.table_access {
+            TableAccess::DirectMap(_) => TablePtr::new(frame.to_virt(self)?.as_mut_ptr()),
+            TableAccess::Kmap { window, .. } => TablePtr::kmap(window, frame),
+            TableAccess::Recursive(RecursiveIndexes { recursive, window }) => {
+                let (active_frame, _) = Cr3::read();
+                if active_frame == frame {
+                    return TablePtr::new(recursive_addr([recursive; 4]).as_mut_ptr());
+                }
+                let active_table =
+                    unsafe { &mut *recursive_addr([recursive; 4]).as_mut_ptr::<PageTable>() };
+                let window_entry = &mut active_table[window];
+                if window_entry.addr() != frame.start_address()
+                    || !window_entry.flags().contains(PageTableFlags::PRESENT)
+                {
+                    window_entry.set_frame(
+                        frame,
+                        PageTableFlags::PRESENT
+                            | PageTableFlags::WRITABLE
+                            | PageTableFlags::NO_EXECUTE,
+                    );
+                    // Anything that was accessed through the window before is now stale
+                    flush_all();
+                }
+                TablePtr::new(
+                    recursive_addr([recursive, recursive, recursive, window]).as_mut_ptr(),
+                )
+            }
+        }
+    }
+
+    /// Gets a pointer to the page table that `entry` points to.
+    /// `entry` must be inside a page table pointer returned by [`PagingConfig::l4_table_ptr`] or this function.
+    pub(crate) fn child_table_ptr(
+        &self,
+        entry: NonNull<PageTableEntry>,
+        frame: PhysFrame,
+    ) -> Option<TablePtr> {
+        match self.table_access {
+            TableAccess::DirectMap(_) | TableAccess::Kmap { .. } => self.new_table_ptr(frame),
+            TableAccess::Recursive(_) => {
+                // Looking up the address of the entry with one more level of recursion leads to the table it points to
+                let entry_addr = entry.as_ptr() as u64;
+                TablePtr::new(VirtAddr::new_truncate(entry_addr << 9).as_mut_ptr())
+            }
+        }
+    }
+
+    /// Gets a pointer to a page table that nothing points to yet.
+    /// Returns `None` in recursive mode, since a table can only be accessed once an entry points to it.
+    pub(crate) fn new_table_ptr(&self, frame: PhysFrame) -> Option<TablePtr> {
+        match self.table_access {
+            TableAccess::DirectMap(_) => TablePtr::new(frame.to_virt(self)?.as_mut_ptr()),
+            TableAccess::Kmap { window, .. } => TablePtr::kmap(window, frame),
+            TableAccess::Recursive(_) => None,
+        }
+    }
+
+    /// Whether a new page table can only be accessed after the entry pointing to it is set
+    pub(crate) fn child_table_needs_entry(&self) -> bool {
+        matches!(self.table_access, TableAccess::Recursive(_))
+    }
+
+    /// Zeroes a new L4 table, and makes it point to itself in recursive mode, or to the window in kmap mode.
+    ///
+    /// # Safety
+    /// The frame must not be in use
+    pub(crate) unsafe fn init_l4_table(&self, frame: PhysFrame) -> Option<()> {
+        let mut table_ptr = self.l4_table_ptr(frame)?;
+        // We use `write_bytes` so that we don't put the 4 KiB page table on the stack, which causes stack overflows.
+        unsafe { table_ptr.ptr.write_bytes(0, 1) };
+        let table = unsafe { table_ptr.ptr.as_mut() };
+        match self.table_access {
+            TableAccess::DirectMap(_) => {}
+            TableAccess::Recursive(RecursiveIndexes { recursive, .. }) => {
+                table[recursive].set_frame(
+                    frame,
+                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+                );
+            }
+            TableAccess::Kmap {
+                window,
+                window_phys_addr,
+            } => unsafe { window.install(table, window_phys_addr) }
+                .expect("the table was just zeroed"),
+        }
+        Some(())
+    }
+}
@@ -1,13 +1,57 @@
-use core::ops::Deref;
+use core::{
+    ops::{Deref, Range},
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use raw_cpuid::CpuId;
+use x86_64::PhysAddr;
 
 #[derive(Debug, Clone, Copy)]
-pub struct VirtualOffset(u64);
+pub struct VirtualOffset {
+    offset: u64,
+    /// `None` means that all physical memory is mapped
+    mapped_ranges: Option<&'static [Range<PhysAddr>]>,
+}
+
+/// The physical memory is not mapped in the direct map
+#[derive(Debug)]
+pub struct NotDirectlyMapped;
 
 impl VirtualOffset {
     /// # Safety
-    /// All physical memory should be mapped starting at this virtual offset
+    /// All physical memory, up to the CPU's maximum physical address, should be mapped starting at this virtual offset
     pub unsafe fn new(virtual_offset: u64) -> Self {
-        Self(virtual_offset)
+        Self {
+            offset: virtual_offset,
+            mapped_ranges: None,
+        }
+    }
+
+    /// Use this if only some physical memory is mapped, for example if firmware and MMIO holes are not mapped.
+    ///
+    /// # Safety
+    /// Every physical address in `mapped_ranges` should be mapped at `physical address + virtual_offset`
+    pub unsafe fn new_sparse(
+        virtual_offset: u64,
+        mapped_ranges: &'static [Range<PhysAddr>],
+    ) -> Self {
+        Self {
+            offset: virtual_offset,
+            mapped_ranges: Some(mapped_ranges),
+        }
+    }
+
+    /// Checks if the whole physical range is mapped
+    pub fn is_mapped(&self, start_addr: PhysAddr, len: u64) -> bool {
+        let Some(end_addr) = start_addr.as_u64().checked_add(len) else {
+            return false;
+        };
+        match self.mapped_ranges {
+            None => end_addr <= 1 << physical_address_bits(),
+            Some(mapped_ranges) => mapped_ranges
+                .iter()
+                .any(|range| range.start <= start_addr && end_addr <= range.end.as_u64()),
+        }
     }
 }
 
@@ -15,6 +59,22 @@ impl Deref for VirtualOffset {
     type Target = u64;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.offset
+    }
+}
+
+/// The number of physical address bits that this CPU supports, which limits the size of a dense direct map
+fn physical_address_bits() -> u8 {
+    static BITS: AtomicU8 = AtomicU8::new(0);
+    match BITS.load(Ordering::Relaxed) {
+        0 => {
+            // The minimum width of 36 bits is used if the CPU does not say
+            let bits = CpuId::new()
+                .get_processor_capacity_feature_info()
+                .map_or(36, |info| info.physical_address_bits());
+            BITS.store(bits, Ordering::Relaxed);
+            bits
+        }
+        bits => bits,
     }
 }